@@ -3,6 +3,9 @@ use core::convert::TryFrom;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+mod error_response;
+pub use error_response::*;
+
 mod operation;
 pub use operation::*;
 
@@ -19,6 +22,12 @@ pub enum MissingError {
     Services,
 }
 
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("service {0} uses allow_with_cache, which needs a backend cache")]
+    AllowWithCacheWithoutCache(String),
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ApplicationKind {
@@ -80,7 +89,8 @@ mod test {
     use threescalers::http::mapping_rule::{Method, RestRule};
 
     use crate::threescale::{
-        Backend, Credentials, Environment, MappingRule, Service, System, Usage,
+        Backend, Credentials, Environment, Extension, MappingRule, OnBackendError, Service, System,
+        Usage,
     };
     use crate::upstream::Upstream;
     use crate::util::glob::GlobPatternSet;
@@ -109,6 +119,22 @@ mod test {
         parsed
     }
 
+    #[test]
+    fn it_requires_a_cache_to_allow_with_cache() {
+        let mut config = get_config();
+        assert!(config.get().check().is_ok());
+
+        let config = config.get_mut();
+        config.services.as_mut().unwrap()[0].on_backend_error = OnBackendError::AllowWithCache;
+        assert!(matches!(
+            config.check(),
+            Err(SettingsError::AllowWithCacheWithoutCache(id)) if id == "2555417834780"
+        ));
+
+        config.backend.as_mut().unwrap().cache = Some(Default::default());
+        assert!(config.check().is_ok());
+    }
+
     fn get_config() -> Configuration {
         Configuration::V1(api::v1::Configuration {
             system: Some(System {
@@ -128,7 +154,9 @@ mod test {
                     url: "https://su1.3scale.net".parse().unwrap(),
                     timeout: Duration::from_millis(5000),
                 },
-                extensions: Some(vec!["no_body".to_string()]),
+                extensions: Some(vec![Extension::try_from("no_body".to_string()).unwrap()]),
+                cache: None,
+                failover: None,
             }),
             services: Some(vec![Service {
                 id: "2555417834780".into(),
@@ -165,6 +193,7 @@ mod test {
                                 tail: Some(1),
                             }),
                         ]),
+                        strip: false,
                     }]),
                     Some(vec![
                         Source::Header {
@@ -231,6 +260,7 @@ mod test {
                                 )])),
                             ]
                             .into(),
+                            strip: false,
                         },
                         Source::Filter {
                             path: vec!["envoy.filters.http.jwt_authn".into(), "0".into()],
@@ -246,24 +276,38 @@ mod test {
                                     keys: vec!["azp".into(), "aud".into()],
                                 }),
                             ]),
+                            strip: false,
                         },
                         Source::Header {
                             keys: vec!["x-app-id".into()],
                             ops: Default::default(),
+                            strip: false,
                         },
                     ]),
                     None,
+                    None,
+                    None,
                 ),
+                matcher: None,
+                credentials_policy: Default::default(),
+                on_backend_error: Default::default(),
+                mode: Default::default(),
+                report_log: None,
+                report_condition: None,
+                error_responses: Default::default(),
+                upstream_headers: Default::default(),
                 mapping_rules: vec![MappingRule {
                     rule: RestRule::new(Method::from("any"), "/").unwrap(),
                     usages: vec![Usage {
                         name: "Hits".into(),
-                        delta: 1,
+                        delta: 1.into(),
                     }],
                     last: Default::default(),
+                    mode: Default::default(),
                 }],
             }]),
             passthrough_metadata: Some(true),
+            error_responses: None,
         })
     }
 
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
-use crate::configuration::MissingError;
-use crate::threescale::{Backend, Service, System};
+use crate::configuration::{ErrorResponses, MissingError, SettingsError};
+use crate::threescale::{Backend, OnBackendError, Service, System};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename = "3scale")]
@@ -11,6 +11,8 @@ pub struct Configuration {
     pub services: Option<Vec<Service>>,
     // pass request to the next filter in the chain
     pub passthrough_metadata: Option<bool>,
+    // defaults for all services
+    pub error_responses: Option<ErrorResponses>,
 }
 
 impl Configuration {
@@ -27,6 +29,10 @@ impl Configuration {
         self.services.as_ref()
     }
 
+    pub const fn error_responses(&self) -> Option<&ErrorResponses> {
+        self.error_responses.as_ref()
+    }
+
     pub fn get_backend(&self) -> Result<&Backend, MissingError> {
         self.backend().ok_or(MissingError::Backend)
     }
@@ -34,4 +40,26 @@ impl Configuration {
     pub fn get_services(&self) -> Result<&Vec<Service>, MissingError> {
         self.services().ok_or(MissingError::Services)
     }
+
+    // Checks settings that depend on others to work.
+    pub fn check(&self) -> Result<(), SettingsError> {
+        let has_cache = self
+            .backend()
+            .map_or(false, |backend| backend.cache().is_some());
+        if has_cache {
+            return Ok(());
+        }
+
+        match self
+            .services()
+            .into_iter()
+            .flatten()
+            .find(|svc| svc.on_backend_error() == OnBackendError::AllowWithCache)
+        {
+            Some(svc) => Err(SettingsError::AllowWithCacheWithoutCache(
+                svc.id().to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
 }
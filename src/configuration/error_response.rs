@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Kinds of failures with a configurable response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClass {
+    UnknownService,
+    NoMappingRule,
+    MissingCredentials,
+    InvalidCredentials,
+    LimitsExceeded,
+    BackendUnavailable,
+    // rejection reasons from backend, falling back to the classes above
+    UserKeyInvalid,
+    ApplicationNotFound,
+    ApplicationKeyInvalid,
+    ApplicationNotActive,
+    ReferrerNotAllowed,
+    OauthNotEnabled,
+    RedirectUriInvalid,
+    // backend does not accept the service's configuration
+    ServiceMisconfigured,
+}
+
+impl FailureClass {
+    // The class to look up a response for when none is configured for this one.
+    pub fn fallback(&self) -> Option<Self> {
+        match self {
+            Self::UserKeyInvalid
+            | Self::ApplicationNotFound
+            | Self::ApplicationKeyInvalid
+            | Self::ApplicationNotActive
+            | Self::ReferrerNotAllowed
+            | Self::OauthNotEnabled
+            | Self::RedirectUriInvalid => Some(Self::InvalidCredentials),
+            _ => None,
+        }
+    }
+}
+
+pub type ErrorResponses = BTreeMap<FailureClass, ErrorResponse>;
+
+// Fields left out keep the values of the default response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u32>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // supports the {request_id}, {service_id} and {status} placeholders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Replaces the {name} placeholders in the template with their values in a
+// single pass, so that values are never expanded themselves.
+pub fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// Request ids are set by clients, so only those made of characters that need
+// no escaping in any content type are rendered.
+pub fn safe_request_id(request_id: &str) -> Option<&str> {
+    let safe = request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    Some(request_id).filter(|_| safe)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_renders_templates() {
+        let rendered = render_template(
+            r#"{"error": "{status}", "request": "{request_id}", "other": "{unknown}"}"#,
+            &[("request_id", "abc-123"), ("status", "403")],
+        );
+        assert_eq!(
+            rendered,
+            r#"{"error": "403", "request": "abc-123", "other": "{unknown}"}"#
+        );
+    }
+
+    #[test]
+    fn it_does_not_expand_values() {
+        let rendered = render_template(
+            "{request_id} {service_id}{",
+            &[("request_id", "{service_id}"), ("service_id", "{status}")],
+        );
+        assert_eq!(rendered, "{service_id} {status}{");
+    }
+
+    #[test]
+    fn it_only_renders_safe_request_ids() {
+        assert_eq!(
+            safe_request_id("f81d4fae-7dec-11d0.a765_00a0c91e6bf6"),
+            Some("f81d4fae-7dec-11d0.a765_00a0c91e6bf6")
+        );
+        assert_eq!(safe_request_id("<script>alert(1)</script>"), None);
+        assert_eq!(safe_request_id("a\"b"), None);
+        assert_eq!(safe_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)), None);
+    }
+}
@@ -4,6 +4,8 @@ use base64::{engine::general_purpose, Engine as _};
 use proxy_wasm::traits::HttpContext;
 use serde::{Deserialize, Serialize};
 
+use crate::util::jwt;
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum DecodeError {
@@ -13,6 +15,8 @@ pub enum DecodeError {
     Base64Error(#[from] base64::DecodeError),
     #[error("invalid utf8 string")]
     Utf8Error(#[from] std::string::FromUtf8Error),
+    #[error("malformed JWT: {0}")]
+    JwtError(#[from] jwt::Error),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +26,10 @@ pub enum Decode {
     Base64,
     #[serde(rename = "base64_urlsafe")]
     Base64UrlSafe,
+    // pushes the JSON payload, not verifying the signature
+    Jwt,
+    // pushes the JSON header, then the JSON payload
+    JwtWithHeader,
 }
 
 impl Decode {
@@ -37,9 +45,70 @@ impl Decode {
             Self::Base64UrlSafe => {
                 String::from_utf8(general_purpose::URL_SAFE.decode(input.as_ref())?)?
             }
+            Self::Jwt | Self::JwtWithHeader => {
+                let token = jwt::Token::parse(input.as_ref())?;
+                // reject tokens not carrying the expected JSON objects
+                let _ = token.header()?;
+                let _ = token.claims()?;
+                if let Self::JwtWithHeader = self {
+                    stack.push(token.header_json().to_string().into());
+                }
+                token.payload_json().to_string()
+            }
         };
 
         stack.push(s.into());
         Ok(stack)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // decoding does not need anything from the proxy
+    struct NoContext;
+    impl proxy_wasm::traits::Context for NoContext {}
+    impl HttpContext for NoContext {}
+
+    // {"alg":"none"}.{"azp":"my-client"}.
+    const TOKEN: &str = "eyJhbGciOiJub25lIn0.eyJhenAiOiJteS1jbGllbnQifQ.";
+
+    fn decode(op: Decode, input: &str) -> Result<Vec<String>, DecodeError> {
+        op.process(&NoContext, vec![input.into()])
+            .map(|stack| stack.into_iter().map(Cow::into_owned).collect())
+    }
+
+    #[test]
+    fn it_decodes_jwt_payloads() {
+        assert_eq!(
+            decode(Decode::Jwt, TOKEN).unwrap(),
+            vec![r#"{"azp":"my-client"}"#]
+        );
+        assert_eq!(
+            decode(Decode::JwtWithHeader, TOKEN).unwrap(),
+            vec![r#"{"alg":"none"}"#, r#"{"azp":"my-client"}"#]
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_jwts() {
+        for input in &[
+            "",
+            "not-a-token",
+            "eyJhbGciOiJub25lIn0.eyJhenAiOiJteS1jbGllbnQifQ",
+            // standard base64 alphabet
+            "eyJhbGciOiJub25lIn0=.eyJhenAiOiJteS1jbGllbnQifQ.",
+            "eyJhbGciOiJub25lIn0.e30+/w.",
+            // not JSON objects
+            "bm9uZQ.eyJhenAiOiJteS1jbGllbnQifQ.",
+            "eyJhbGciOiJub25lIn0.WzFd.",
+        ] {
+            assert!(
+                matches!(decode(Decode::Jwt, input), Err(DecodeError::JwtError(_))),
+                "accepted {:?}",
+                input
+            );
+        }
+    }
+}
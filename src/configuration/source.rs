@@ -1,16 +1,21 @@
 use std::borrow::Cow;
 
 use log::debug;
+use proxy_wasm::traits::HttpContext;
 use serde::{Deserialize, Serialize};
 
 use super::Operation;
 use crate::proxy::{
     metadata::{Metadata, ValueExt},
+    property,
     request_headers::RequestHeaders,
     HttpAuthThreescale,
 };
+use crate::util::path_template::PathTemplate;
 
 const METADATA: &[&str] = &["metadata"];
+pub const DEFAULT_BODY_MAX_SIZE: usize = 16384;
+const DEFAULT_BASIC_AUTH_REALM: &str = "3scale";
 //TODO static METADATA_VEC: Vec<&str> = METADATA.into(); // via lazy_static or some similar mechanism
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,11 +25,60 @@ pub enum Source {
         keys: Vec<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         ops: Option<Vec<Operation>>,
+        // remove the keys from the request once authorized
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        strip: bool,
     },
     QueryString {
         keys: Vec<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         ops: Option<Vec<Operation>>,
+        // remove the keys from the request once authorized
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        strip: bool,
+    },
+    Cookie {
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+        // remove the keys from the request once authorized
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        strip: bool,
+    },
+    Path {
+        template: PathTemplate,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+    },
+    Body {
+        // only used for JSON bodies
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        path: Vec<String>,
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+        #[serde(default = "defaults::body_max_size")]
+        max_size: usize,
+    },
+    // Authorization header using the Basic scheme, resolving to user and password
+    BasicAuth {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        realm: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+        // remove the keys from the request once authorized
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        strip: bool,
+    },
+    Property {
+        path: Vec<String>,
+        // only used for map properties such as request.headers
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
     },
     Filter {
         #[serde(default)]
@@ -36,7 +90,78 @@ pub enum Source {
     },
 }
 
+mod defaults {
+    pub(super) fn body_max_size() -> usize {
+        super::DEFAULT_BODY_MAX_SIZE
+    }
+}
+
 impl Source {
+    // The amount of request body to buffer, if this source needs it.
+    pub fn body_limit(&self) -> Option<usize> {
+        match self {
+            Source::Body { max_size, .. } => Some(*max_size),
+            _ => None,
+        }
+    }
+
+    // The realm to challenge clients with when credentials are missing.
+    pub fn basic_auth_realm(&self) -> Option<&str> {
+        match self {
+            Source::BasicAuth { realm, .. } => {
+                Some(realm.as_deref().unwrap_or(DEFAULT_BASIC_AUTH_REALM))
+            }
+            _ => None,
+        }
+    }
+
+    // Removes what this source looks up from the request going upstream, if
+    // it is asked to. Body, path and other sources cannot be stripped.
+    pub fn strip(&self, ctx: &dyn HttpContext) {
+        match self {
+            Source::Header {
+                keys, strip: true, ..
+            } => {
+                for key in keys {
+                    ctx.set_http_request_header(key, None);
+                }
+            }
+            Source::QueryString {
+                keys, strip: true, ..
+            } => {
+                let stripped = ctx
+                    .get_http_request_header(":path")
+                    .and_then(|path| strip_query_keys(path.as_str(), keys));
+                if let Some(path) = stripped {
+                    debug!("stripped query string keys {:?}", keys);
+                    ctx.set_http_request_header(":path", Some(path.as_str()));
+                }
+            }
+            Source::Cookie {
+                keys, strip: true, ..
+            } => {
+                let rh = RequestHeaders::new(ctx);
+                if let Some(cookies) = rh.cookies_without(keys) {
+                    debug!("stripped cookies {:?}", keys);
+                    ctx.set_http_request_header("cookie", None);
+                    for cookie in cookies {
+                        ctx.add_http_request_header("cookie", cookie.as_str());
+                    }
+                }
+            }
+            Source::BasicAuth { strip: true, .. } => {
+                let rh = RequestHeaders::new(ctx);
+                if rh
+                    .get_all("authorization")
+                    .any(|value| decode_basic_auth(value).is_some())
+                {
+                    ctx.set_http_request_header("authorization", None);
+                }
+            }
+            _ => (),
+        }
+    }
+
     pub fn resolve<'url, 'a: 'url>(
         &'a self,
         ctx: &'a HttpAuthThreescale,
@@ -46,7 +171,7 @@ impl Source {
         use proxy_wasm::traits::Context;
 
         let res = match self {
-            Source::QueryString { keys, ops } => {
+            Source::QueryString { keys, ops, .. } => {
                 keys.iter().map(std::ops::Deref::deref).find_map(|key| {
                     url.query_pairs().find_map(|(k, v)| {
                         if key == k.as_ref() {
@@ -58,7 +183,7 @@ impl Source {
                     })
                 })
             }
-            Source::Header { keys, ops } => {
+            Source::Header { keys, ops, .. } => {
                 debug!("looking for headers");
                 keys.iter().map(std::ops::Deref::deref).find_map(|key| {
                     debug!("looking for header {}", key);
@@ -68,6 +193,116 @@ impl Source {
                     })
                 })
             }
+            Source::Cookie { keys, ops, .. } => {
+                keys.iter().map(std::ops::Deref::deref).find_map(|key| {
+                    rh.get_cookie(key).map(|v| {
+                        debug!("found cookie {} - ops {:?}", key, ops);
+                        (vec![Cow::from(v)], ops)
+                    })
+                })
+            }
+            Source::Path {
+                template,
+                keys,
+                ops,
+            } => template.captures(url.path()).and_then(|captures| {
+                // values are pushed following the order of keys, or the template's if none
+                let values = if keys.is_empty() {
+                    captures.iter().map(|&(_, v)| v).collect::<Vec<_>>()
+                } else {
+                    keys.iter()
+                        .filter_map(|key| {
+                            captures.iter().find_map(|&(name, v)| {
+                                if name == key.as_str() {
+                                    Some(v)
+                                } else {
+                                    None
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                };
+                debug!("path template {:?} captured {:?}", template, values);
+
+                if values.is_empty() {
+                    None
+                } else {
+                    Some((
+                        values
+                            .into_iter()
+                            .map(|v| {
+                                Cow::from(
+                                    percent_encoding::percent_decode_str(v)
+                                        .decode_utf8_lossy()
+                                        .into_owned(),
+                                )
+                            })
+                            .collect(),
+                        ops,
+                    ))
+                }
+            }),
+            Source::Body {
+                path, keys, ops, ..
+            } => {
+                let body = ctx.request_body()?;
+                let content_type = rh.get("content-type").unwrap_or_default();
+                resolve_body(content_type, body, path, keys).map(|v| {
+                    debug!("found body parameter with value {} - ops {:?}", v, ops);
+                    (vec![Cow::from(v)], ops)
+                })
+            }
+            Source::BasicAuth { ops, .. } => rh
+                .get_all("authorization")
+                .find_map(decode_basic_auth)
+                .map(|(user, password)| {
+                    debug!(
+                        "found basic auth credentials for user {} - ops {:?}",
+                        user, ops
+                    );
+                    let mut values = vec![Cow::from(user)];
+                    // an empty password means no app_key
+                    if !password.is_empty() {
+                        values.push(Cow::from(password));
+                    }
+                    (values, ops)
+                }),
+            Source::Property { path, keys, ops } => {
+                if path.is_empty() {
+                    return None;
+                }
+                let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+                debug!("looking up host property {:?}", path);
+                let property = ctx.get_property(path)?;
+
+                let value = if keys.is_empty() {
+                    String::from_utf8(property)
+                        .map_err(|_| debug!("host property is not a valid UTF-8 string"))
+                        .ok()
+                } else {
+                    property::deserialize_pairs(property.as_slice())
+                        .or_else(|| {
+                            debug!("host property is not a map");
+                            None
+                        })
+                        .and_then(|pairs| {
+                            keys.iter().find_map(|key| {
+                                pairs.iter().find_map(|(k, v)| {
+                                    if k == key {
+                                        Some(v.to_string())
+                                    } else {
+                                        None
+                                    }
+                                })
+                            })
+                        })
+                };
+
+                value.map(|v| {
+                    debug!("found host property with value {} - ops {:?}", v, ops);
+                    (vec![Cow::from(v)], ops)
+                })
+            }
             Source::Filter { path, keys, ops } => {
                 let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
                 let path = if path.is_empty() {
@@ -167,3 +402,192 @@ impl Source {
         })
     }
 }
+
+// Decodes user and password from an Authorization header value using the
+// Basic scheme, which is matched case-insensitively.
+fn decode_basic_auth(value: &str) -> Option<(String, String)> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = general_purpose::STANDARD
+        .decode(credentials.trim())
+        .map_err(|e| debug!("failed to decode basic auth credentials: {}", e))
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    if user.is_empty() {
+        return None;
+    }
+
+    Some((user.to_string(), password.to_string()))
+}
+
+// Removes the parameters with any of the keys from the query string of the
+// path, leaving the rest as they were. Returns None if none was found.
+fn strip_query_keys(path: &str, keys: &[String]) -> Option<String> {
+    let (path, qs) = path.split_once('?')?;
+
+    let mut kept = vec![];
+    for pair in qs.split('&') {
+        let key = url::form_urlencoded::parse(pair.as_bytes())
+            .next()
+            .map(|(k, _)| k);
+        match key {
+            Some(key) if keys.iter().any(|k| k.as_str() == key.as_ref()) => (),
+            _ => kept.push(pair),
+        }
+    }
+
+    if kept.len() == qs.split('&').count() {
+        None
+    } else if kept.is_empty() {
+        Some(path.to_string())
+    } else {
+        Some(format!("{}?{}", path, kept.join("&")))
+    }
+}
+
+fn resolve_body(
+    content_type: &str,
+    body: &[u8],
+    path: &[String],
+    keys: &[String],
+) -> Option<String> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    if mime == "application/x-www-form-urlencoded" {
+        keys.iter().find_map(|key| {
+            url::form_urlencoded::parse(body).find_map(|(k, v)| {
+                if k == key.as_str() {
+                    Some(v.into_owned())
+                } else {
+                    None
+                }
+            })
+        })
+    } else if mime == "application/json" || mime.ends_with("+json") {
+        let json = match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(json) => json,
+            Err(e) => {
+                debug!("failed to parse JSON body: {}", e);
+                return None;
+            }
+        };
+        let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        json.lookup(path.as_slice())
+            .ok()
+            .and_then(|(v, _segment)| v.match_one(keys.as_slice()))
+            .and_then(|v| ValueExt::as_str(v))
+            .map(str::to_string)
+    } else {
+        debug!("unsupported body content type {:?}", content_type);
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_strips_query_keys() {
+        let keys = vec!["user_key".to_string(), "app id".to_string()];
+
+        assert_eq!(
+            strip_query_keys("/products?user_key=abc&page=2&q=a%26b", &keys).as_deref(),
+            Some("/products?page=2&q=a%26b")
+        );
+        assert_eq!(
+            strip_query_keys("/products?app+id=abc&user_key", &keys).as_deref(),
+            Some("/products")
+        );
+        assert_eq!(strip_query_keys("/products?page=2", &keys), None);
+        assert_eq!(strip_query_keys("/products", &keys), None);
+    }
+
+    #[test]
+    fn it_resolves_body_parameters() {
+        let keys = vec!["user_key".to_string(), "app_id".to_string()];
+
+        assert_eq!(
+            resolve_body(
+                "application/x-www-form-urlencoded; charset=utf-8",
+                b"page=2&app_id=a%20b",
+                &[],
+                &keys
+            )
+            .as_deref(),
+            Some("a b")
+        );
+
+        let json = br#"{"auth": {"credentials": {"user_key": "abc"}}, "user_key": "top"}"#;
+        let path = vec!["auth".to_string(), "credentials".to_string()];
+        assert_eq!(
+            resolve_body("application/json", json, &path, &keys).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            resolve_body("application/vnd.api+json", json, &[], &keys).as_deref(),
+            Some("top")
+        );
+        assert_eq!(resolve_body("application/json", b"{", &[], &keys), None);
+        assert_eq!(
+            resolve_body("text/plain", b"user_key=abc", &[], &keys),
+            None
+        );
+    }
+
+    #[test]
+    fn it_limits_body_lookups() {
+        let source: Source = serde_json::from_str(r#"{"body": {"keys": ["user_key"]}}"#).unwrap();
+        assert_eq!(source.body_limit(), Some(defaults::body_max_size()));
+
+        let source: Source =
+            serde_json::from_str(r#"{"body": {"keys": ["user_key"], "max_size": 512}}"#).unwrap();
+        assert_eq!(source.body_limit(), Some(512));
+    }
+
+    #[test]
+    fn it_decodes_basic_auth() {
+        let fixtures = [
+            (
+                "Basic YWxhZGRpbjpvcGVuc2VzYW1l",
+                Some(("aladdin", "opensesame")),
+            ),
+            (
+                "bAsIc   YWxhZGRpbjpvcGVuc2VzYW1l ",
+                Some(("aladdin", "opensesame")),
+            ),
+            ("Basic YWxhZGRpbjo=", Some(("aladdin", ""))),
+            (
+                "Basic YWxhZGRpbjpvcGVuOnNlc2FtZQ==",
+                Some(("aladdin", "open:sesame")),
+            ),
+            ("Basic OnBhc3N3b3Jk", None),
+            ("Basic YWxhZGRpbg==", None),
+            ("Basic not-base64!", None),
+            ("Bearer YWxhZGRpbjpvcGVuc2VzYW1l", None),
+            ("YWxhZGRpbjpvcGVuc2VzYW1l", None),
+        ];
+
+        for (value, expected) in fixtures.iter() {
+            let res = decode_basic_auth(value);
+            assert_eq!(
+                res.as_ref().map(|(u, p)| (u.as_str(), p.as_str())),
+                *expected,
+                "failed decoding {:?}",
+                value
+            );
+        }
+    }
+}
@@ -1,6 +1,13 @@
+mod app_info;
 mod authrep;
+mod backend_health;
+mod cache;
 pub mod config_fetcher;
 pub mod metadata;
+pub mod property;
+mod rate_limit;
+mod rejection;
+mod report_queue;
 pub mod request_headers;
 
 mod http_context;
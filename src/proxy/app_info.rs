@@ -0,0 +1,160 @@
+// Data about authorized applications that services can ask to send upstream,
+// taken from their credentials and from the authorization response.
+//
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{MetricType, Status};
+use serde::Serialize;
+use threescalers::response::Authorization;
+
+use super::cache::App;
+use crate::threescale::UpstreamData;
+
+const PARSE_ERRORS_METRIC: &str = "threescale_wasm_auth.upstream_data_errors";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageReport {
+    pub metric: String,
+    pub period: String,
+    pub current_value: u64,
+    pub max_value: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AppInfo {
+    app_id: Option<String>,
+    // OAuth authorizations answer with a different document
+    oauth: bool,
+    plan: Option<String>,
+    usage_reports: Vec<UsageReport>,
+}
+
+impl AppInfo {
+    pub fn new(app: &App) -> Self {
+        let app_id = match app {
+            App::AppId(app_id, _) => Some(app_id.clone()),
+            // the rest of credentials are secrets
+            App::UserKey(_) | App::AccessToken(_) => None,
+        };
+
+        Self {
+            app_id,
+            oauth: matches!(app, App::AccessToken(_)),
+            ..Default::default()
+        }
+    }
+
+    // Takes the plan and usage reports from the XML body of a successful
+    // authorization response, other than OAuth ones, which are not supported.
+    pub fn parse_response(&mut self, body: &[u8]) -> Result<(), anyhow::Error> {
+        if self.oauth {
+            return Ok(());
+        }
+        let body = std::str::from_utf8(body)?;
+        let status = match Authorization::from_str(body)
+            .map_err(|e| anyhow::anyhow!("failed to parse authorization response: {:?}", e))?
+        {
+            Authorization::Status(status) => status,
+            _ => anyhow::bail!("authorization response is not a status"),
+        };
+
+        self.plan = Some(status.plan().to_string());
+        self.usage_reports = status
+            .usage_reports()
+            .map(|reports| {
+                reports
+                    .iter()
+                    .map(|report| UsageReport {
+                        metric: report.metric.clone(),
+                        period: format!("{:?}", report.period).to_ascii_lowercase(),
+                        current_value: report.current_value,
+                        max_value: report.max_value,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(())
+    }
+
+    // The header value for the data, if known.
+    pub fn value(&self, data: UpstreamData) -> Option<String> {
+        match data {
+            UpstreamData::Plan => self.plan.clone(),
+            UpstreamData::AppId => self.app_id.clone(),
+            UpstreamData::UsageReports if self.usage_reports.is_empty() => None,
+            UpstreamData::UsageReports => serde_json::to_string(&self.usage_reports).ok(),
+        }
+    }
+
+    // The values of the headers the service asks for, with None for those to
+    // remove from the request since no data is known for them.
+    pub fn headers<'a>(
+        &self,
+        upstream_headers: &'a BTreeMap<String, UpstreamData>,
+    ) -> Vec<(&'a str, Option<String>)> {
+        upstream_headers
+            .iter()
+            .map(|(name, &data)| (name.as_str(), self.value(data)))
+            .collect()
+    }
+}
+
+// Counts authorization responses whose data could not be sent upstream.
+pub fn record_parse_error() -> Result<(), Status> {
+    // defining an existing metric returns its id
+    let metric = hostcalls::define_metric(MetricType::Counter, PARSE_ERRORS_METRIC)?;
+    hostcalls::increment_metric(metric, 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_only_exposes_app_ids() {
+        let info = AppInfo::new(&App::AppId("app".into(), Some("secret".into())));
+        assert_eq!(info.value(UpstreamData::AppId).as_deref(), Some("app"));
+        assert_eq!(info.value(UpstreamData::Plan), None);
+
+        let info = AppInfo::new(&App::UserKey("secret".into()));
+        assert_eq!(info.value(UpstreamData::AppId), None);
+    }
+
+    #[test]
+    fn it_does_not_parse_oauth_responses() {
+        let mut info = AppInfo::new(&App::AccessToken("token".into()));
+        assert!(info
+            .parse_response(b"<status><application/></status>")
+            .is_ok());
+        assert_eq!(info.value(UpstreamData::Plan), None);
+
+        let mut info = AppInfo::new(&App::UserKey("secret".into()));
+        assert!(info.parse_response(b"\xff").is_err());
+    }
+
+    // letting requests through when backend fails leaves no response to
+    // take the plan and usages from
+    #[test]
+    fn it_removes_unknown_headers_without_authorization_response() {
+        let upstream_headers = vec![
+            ("x-app-id".to_string(), UpstreamData::AppId),
+            ("x-plan".to_string(), UpstreamData::Plan),
+            ("x-usage".to_string(), UpstreamData::UsageReports),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        let info = AppInfo::new(&App::AppId("app".into(), None));
+        assert_eq!(
+            info.headers(&upstream_headers),
+            vec![
+                ("x-app-id", Some("app".to_string())),
+                ("x-plan", None),
+                ("x-usage", None),
+            ]
+        );
+    }
+}
@@ -1,8 +1,12 @@
+use core::convert::TryFrom;
 use std::collections::HashMap;
 
+use super::report_queue::PendingUsage;
 use super::request_headers::RequestHeaders;
 use super::HttpAuthThreescale;
-use crate::threescale::CredentialsError;
+use crate::threescale::{
+    parse_delta, Computed, CredentialsError, Delta, Extension, Mode, ResponseHeader,
+};
 use threescalers::{
     api_call::{ApiCall, Kind},
     application::Application,
@@ -28,6 +32,9 @@ pub struct AuthRep<'a> {
     service: &'a crate::threescale::Service,
     apps: Vec<Application>,
     usages: HashMap<&'a str, i64>,
+    // deltas to compute once upstream responds
+    response_usages: Vec<(&'a str, &'a ResponseHeader)>,
+    mode: Mode,
 }
 
 impl<'a> AuthRep<'a> {
@@ -42,25 +49,59 @@ impl<'a> AuthRep<'a> {
     pub fn usages(&self) -> &HashMap<&'a str, i64> {
         &self.usages
     }
+
+    pub fn response_usages(&self) -> &Vec<(&'a str, &'a ResponseHeader)> {
+        &self.response_usages
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
 }
 
 #[allow(dead_code)]
 pub fn request(ctx: &HttpAuthThreescale, rh: &RequestHeaders) -> Result<Request, anyhow::Error> {
     let ar = authrep(ctx, rh)?;
-    build_call(&ar)
+    let extensions = ctx
+        .configuration()
+        .backend()
+        .and_then(|backend| backend.extensions());
+    build_call(&ar, extensions)
+}
+
+pub fn match_service<'a>(
+    ctx: &'a HttpAuthThreescale,
+    rh: &RequestHeaders,
+    url: &url::Url,
+) -> Result<&'a crate::threescale::Service, anyhow::Error> {
+    let svclist = ctx.configuration().get_services()?;
+    let authority = url.authority();
+    let metadata = rh.metadata();
+
+    // the most specific match wins, and the first one configured on ties
+    let service = svclist
+        .iter()
+        .filter_map(|svc| {
+            svc.match_request(authority, metadata.method(), url.path(), rh)
+                .map(|specificity| (specificity, svc))
+        })
+        .fold(None, |best, (specificity, svc)| match best {
+            Some((best_specificity, _)) if best_specificity >= specificity => best,
+            _ => Some((specificity, svc)),
+        })
+        .map(|(_, svc)| svc)
+        .ok_or(MatchError::NoServiceMatched)?;
+
+    Ok(service)
 }
 
 pub fn authrep<'a>(
     ctx: &'a HttpAuthThreescale,
     rh: &'a RequestHeaders,
 ) -> Result<AuthRep<'a>, anyhow::Error> {
-    let config = ctx.configuration();
-    let svclist = config.get_services()?;
-
     let metadata = rh.metadata();
     let method = Method::from(metadata.method());
     let url = rh.url()?;
-    let authority = url.authority();
     let path = url.path();
     let mut pattern = path.to_string();
     let qs = url.query();
@@ -69,15 +110,12 @@ pub fn authrep<'a>(
         pattern.push_str(qs);
     }
 
-    let service = svclist
-        .iter()
-        .find(|&svc| svc.match_authority(authority))
-        .ok_or(MatchError::NoServiceMatched)?;
+    let service = match_service(ctx, rh, &url)?;
 
     let credentials = service.credentials();
 
     let apps = credentials
-        .resolve(ctx, rh, &url)
+        .resolve(ctx, rh, &url, service.credentials_policy())
         .map_err(MatchError::CredentialsError)?;
 
     debug!(ctx, "found credentials, values {:#?}", apps);
@@ -89,6 +127,9 @@ pub fn authrep<'a>(
     }
 
     let mut usages = std::collections::HashMap::new();
+    let mut response_usages = Vec::new();
+    // the first matching rule specifying a mode decides it
+    let mut rule_mode = None;
     for rule in service.mapping_rules() {
         debug!(
             ctx,
@@ -99,9 +140,34 @@ pub fn authrep<'a>(
         if rule.is_match(&method, pattern.as_str()) {
             debug!(ctx, "matched pattern in {}", pattern);
             for usage in rule.usages() {
+                let delta = match usage.delta() {
+                    Delta::Static(delta) => *delta,
+                    Delta::Computed(Computed::Request(sources)) => sources
+                        .iter()
+                        .find_map(|source| source.resolve(ctx, rh, &url))
+                        .and_then(|values| values.first().and_then(|value| parse_delta(value)))
+                        .unwrap_or_else(|| {
+                            debug!(ctx, "no delta found for {}, using 0", usage.name());
+                            0
+                        }),
+                    // bodies sent without a length only have a size once buffered
+                    Delta::Computed(Computed::RequestBodySize) => rh
+                        .content_length()
+                        .or_else(|| ctx.request_body().map(|body| body.len() as u64))
+                        .and_then(|size| i64::try_from(size).ok())
+                        .unwrap_or_else(|| {
+                            debug!(ctx, "no body size for {}, using 0", usage.name());
+                            0
+                        }),
+                    Delta::Computed(Computed::Response(header)) => {
+                        response_usages.push((usage.name(), header));
+                        continue;
+                    }
+                };
                 let value = usages.entry(usage.name()).or_insert(0);
-                *value += usage.delta();
+                *value += delta;
             }
+            rule_mode = rule_mode.or_else(|| rule.mode());
             if rule.is_last() {
                 debug!(ctx, "rule is last, stopping further evaluation");
                 break;
@@ -109,18 +175,31 @@ pub fn authrep<'a>(
         }
     }
 
-    if usages.is_empty() {
+    if usages.is_empty() && response_usages.is_empty() {
         anyhow::bail!(MatchError::NoUsageMatch);
     }
 
+    let mode = match rule_mode.unwrap_or_else(|| service.mode()) {
+        // reporting conditions and response deltas need the upstream response
+        Mode::AuthRep if service.report_condition().is_some() || !response_usages.is_empty() => {
+            Mode::DeferredReport
+        }
+        mode => mode,
+    };
+
     Ok(AuthRep {
         service,
         apps,
         usages,
+        response_usages,
+        mode,
     })
 }
 
-pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
+pub fn build_call(
+    ar: &AuthRep,
+    extensions: Option<&Vec<Extension>>,
+) -> Result<Request, anyhow::Error> {
     let apps = ar.apps();
 
     if apps.is_empty() {
@@ -134,22 +213,73 @@ pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
     //    );
     //}
 
-    let app = &apps[0];
+    build_app_call(ar, &apps[0], extensions)
+}
+
+// Builds one call per resolved application, in order.
+pub fn build_calls(
+    ar: &AuthRep,
+    extensions: Option<&Vec<Extension>>,
+) -> Result<Vec<Request>, anyhow::Error> {
+    if ar.apps().is_empty() {
+        anyhow::bail!(CredentialsError::NotFound);
+    }
+
+    ar.apps()
+        .iter()
+        .map(|app| build_app_call(ar, app, extensions))
+        .collect()
+}
+
+// Deferred reports are sent apart once upstream responds.
+fn call_kind(mode: Mode) -> Kind {
+    match mode {
+        Mode::AuthRep => Kind::AuthRep,
+        Mode::Authorize | Mode::DeferredReport => Kind::Authorize,
+        Mode::Report => Kind::Report,
+    }
+}
 
+pub fn build_app_call(
+    ar: &AuthRep,
+    app: &Application,
+    extensions: Option<&Vec<Extension>>,
+) -> Result<Request, anyhow::Error> {
     let usage = ar
         .usages()
         .iter()
         .map(|(k, v)| (k, format!("{}", v)))
         .collect::<Vec<_>>();
     let usage = Usage::new(usage.as_slice());
+    // OAuth access tokens make threescalers target the oauth_authrep endpoint
     let txn = Transaction::new(app, None, Some(&usage), None);
     let txns = vec![txn];
-    let extensions = extensions::List::new()
-        .no_body()
-        .push_other("rejection_reason_header".into(), "1".into());
+    // we always rely on these, the rest come from the backend configuration
+    let extensions = extensions
+        .into_iter()
+        .flatten()
+        .filter(|ext| {
+            !matches!(
+                ext.name(),
+                "no_body" | "rejection_reason_header" | "limit_headers"
+            )
+        })
+        .fold(
+            extensions::List::new()
+                .push_other("rejection_reason_header".into(), "1".into())
+                .push_other("limit_headers".into(), "1".into()),
+            |list, ext| list.push_other(ext.name().into(), ext.value().into()),
+        );
 
     let service = ar.service();
 
+    // response bodies are only of use to send their data upstream
+    let extensions = if service.upstream_headers().is_empty() {
+        extensions.no_body()
+    } else {
+        extensions
+    };
+
     let service_token = if let Some(token) = service.token() {
         token
     } else {
@@ -159,13 +289,189 @@ pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
         service.id(),
         threescalers::credentials::Credentials::ServiceToken(service_token.into()),
     );
+    let kind = call_kind(ar.mode());
     let mut apicall = ApiCall::builder(&service);
     // the builder here can only fail if we fail to set a kind
     let apicall = apicall
         .transactions(&txns)
         .extensions(&extensions)
-        .kind(Kind::AuthRep)
+        .kind(kind)
         .build()?;
 
     Ok(Request::from(&apicall))
 }
+
+// Builds a single report call with a transaction per application.
+pub fn build_report(
+    service: &crate::threescale::Service,
+    pending: &[PendingUsage],
+) -> Result<Request, anyhow::Error> {
+    let apps = pending
+        .iter()
+        .map(|p| Application::from(&p.app))
+        .collect::<Vec<_>>();
+    let usages = pending
+        .iter()
+        .map(|p| {
+            p.usages
+                .iter()
+                .map(|(k, v)| (k, format!("{}", v)))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let usages = usages
+        .iter()
+        .map(|usage| Usage::new(usage.as_slice()))
+        .collect::<Vec<_>>();
+    let txns = apps
+        .iter()
+        .zip(usages.iter())
+        .map(|(app, usage)| Transaction::new(app, None, Some(usage), None))
+        .collect::<Vec<_>>();
+
+    let service_token = if let Some(token) = service.token() {
+        token
+    } else {
+        anyhow::bail!("service token unavailable");
+    };
+    let service = Service::new(
+        service.id(),
+        threescalers::credentials::Credentials::ServiceToken(service_token.into()),
+    );
+    let mut apicall = ApiCall::builder(&service);
+    let apicall = apicall.transactions(&txns).kind(Kind::Report).build()?;
+
+    Ok(Request::from(&apicall))
+}
+
+// Response headers safe to send to 3scale, leaving out cookies and any other
+// one that could carry secrets.
+const RESPONSE_LOG_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "cache-control",
+];
+
+// The status line and the allowed headers of the upstream response.
+pub fn response_log(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            name == ":status"
+                || RESPONSE_LOG_HEADERS
+                    .iter()
+                    .any(|allowed| name.eq_ignore_ascii_case(allowed))
+        })
+        .map(|(name, value)| format!("{}: {}\n", name, value))
+        .collect()
+}
+
+// Transaction logs are not supported by threescalers, so their parameters are
+// encoded here to be appended to those of a single transaction report.
+pub fn transaction_log_params(
+    code: Option<u32>,
+    request: Option<&str>,
+    response: Option<&str>,
+) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(code) = code {
+        params.append_pair("transactions[0][log][code]", code.to_string().as_str());
+    }
+    if let Some(request) = request {
+        params.append_pair("transactions[0][log][request]", request);
+    }
+    if let Some(response) = response {
+        params.append_pair("transactions[0][log][response]", response);
+    }
+    params.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::proxy::cache::App;
+
+    fn params(request: &Request) -> Vec<(String, String)> {
+        let (uri, body) = request.uri_and_body();
+        let query = uri.split_once('?').map(|(_, qs)| qs).unwrap_or_default();
+        url::form_urlencoded::parse(query.as_bytes())
+            .chain(url::form_urlencoded::parse(
+                body.unwrap_or_default().as_bytes(),
+            ))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn it_calls_backend_as_per_mode() {
+        assert!(matches!(call_kind(Mode::AuthRep), Kind::AuthRep));
+        assert!(matches!(call_kind(Mode::Authorize), Kind::Authorize));
+        assert!(matches!(call_kind(Mode::Report), Kind::Report));
+        assert!(matches!(call_kind(Mode::DeferredReport), Kind::Authorize));
+    }
+
+    #[test]
+    fn it_builds_a_transaction_per_app() {
+        let service: crate::threescale::Service = serde_json::from_str(
+            r#"{"id": "42", "token": "secret", "authorities": ["*"], "credentials": {"user_key": [{"header": {"keys": ["x-key"]}}]}}"#,
+        )
+        .unwrap();
+        let pending = vec![
+            PendingUsage {
+                app: App::UserKey("key".into()),
+                usages: vec![("hits".to_string(), 3)].into_iter().collect(),
+                oldest: 0,
+            },
+            PendingUsage {
+                app: App::AppId("app".into(), None),
+                usages: BTreeMap::new(),
+                oldest: 0,
+            },
+        ];
+
+        let params = params(&build_report(&service, &pending).unwrap());
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(param("service_id"), Some("42"));
+        assert_eq!(param("transactions[0][user_key]"), Some("key"));
+        assert_eq!(param("transactions[0][usage][hits]"), Some("3"));
+        assert_eq!(param("transactions[1][app_id]"), Some("app"));
+        assert!(!params.iter().any(|(k, _)| k.starts_with("transactions[2]")));
+    }
+
+    #[test]
+    fn it_only_logs_allowed_response_headers() {
+        let headers = [
+            (":status", "200"),
+            ("content-type", "application/json"),
+            ("set-cookie", "session=secret"),
+            ("authorization", "Bearer secret"),
+            ("x-api-token", "secret"),
+            ("Content-Length", "2"),
+        ]
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            response_log(&headers),
+            ":status: 200\ncontent-type: application/json\nContent-Length: 2\n"
+        );
+    }
+
+    #[test]
+    fn it_encodes_transaction_logs() {
+        assert_eq!(
+            transaction_log_params(Some(201), Some("POST /a b?c=d&e"), None),
+            "transactions%5B0%5D%5Blog%5D%5Bcode%5D=201&transactions%5B0%5D%5Blog%5D%5Brequest%5D=POST+%2Fa+b%3Fc%3Dd%26e"
+        );
+        assert_eq!(transaction_log_params(None, None, None), "");
+    }
+}
@@ -0,0 +1,148 @@
+// Passive health of backend upstreams.
+//
+// Each worker's root context tracks it from the outcome of the calls made by
+// its HTTP contexts, which share it, so that failing upstreams are avoided
+// for a while.
+//
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::threescale::{Backend, FailoverStrategy};
+
+pub type SharedHealth = Rc<RefCell<BackendHealth>>;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    failures: u32,
+    unhealthy_until: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+pub struct BackendHealth {
+    states: Vec<State>,
+}
+
+impl BackendHealth {
+    pub fn new_shared() -> SharedHealth {
+        Rc::new(RefCell::new(Self::default()))
+    }
+
+    pub fn reset(&mut self, upstreams: usize) {
+        self.states = vec![State::default(); upstreams];
+    }
+
+    fn is_healthy(&self, idx: usize, now: SystemTime) -> bool {
+        self.states
+            .get(idx)
+            .and_then(|state| state.unhealthy_until)
+            .map_or(true, |until| now >= until)
+    }
+
+    // Indexes of the backend's upstreams in the order to try them: healthy
+    // ones as per the failover strategy, then unhealthy ones as a last resort.
+    pub fn candidates(
+        &self,
+        backend: &Backend,
+        now: SystemTime,
+        mut random: impl FnMut() -> u32,
+    ) -> Vec<usize> {
+        let weights = backend
+            .upstreams()
+            .iter()
+            .map(|&(_, weight)| weight)
+            .collect::<Vec<_>>();
+        let strategy = backend
+            .failover()
+            .map(|failover| failover.strategy())
+            .unwrap_or_default();
+
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+            (0..weights.len()).partition(|&idx| self.is_healthy(idx, now));
+
+        if strategy == FailoverStrategy::Weighted {
+            healthy = weighted_order(healthy, weights.as_slice(), &mut random);
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    pub fn record_success(&mut self, idx: usize) {
+        if let Some(state) = self.states.get_mut(idx) {
+            *state = State::default();
+        }
+    }
+
+    // Returns whether the upstream just became unhealthy.
+    pub fn record_failure(&mut self, backend: &Backend, idx: usize, now: SystemTime) -> bool {
+        let failover = match backend.failover() {
+            Some(failover) => failover,
+            // nothing to fail over to
+            None => return false,
+        };
+        let state = match self.states.get_mut(idx) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        state.failures = state.failures.saturating_add(1);
+        if state.failures < failover.max_failures() {
+            return false;
+        }
+
+        // failures after a cooldown make it unhealthy again right away
+        let was_healthy = state.unhealthy_until.map_or(true, |until| now >= until);
+        state.unhealthy_until = now.checked_add(failover.cooldown());
+        was_healthy
+    }
+}
+
+// Picks indexes randomly as per their weights, without replacement. Those
+// weighing 0 go last.
+fn weighted_order(
+    mut idxs: Vec<usize>,
+    weights: &[u32],
+    random: &mut impl FnMut() -> u32,
+) -> Vec<usize> {
+    let mut ordered = Vec::with_capacity(idxs.len());
+
+    loop {
+        let total = idxs.iter().map(|&idx| u64::from(weights[idx])).sum::<u64>();
+        if total == 0 {
+            break;
+        }
+
+        let mut pick = u64::from(random()) % total;
+        let pos = idxs
+            .iter()
+            .position(|&idx| {
+                let weight = u64::from(weights[idx]);
+                if pick < weight {
+                    true
+                } else {
+                    pick -= weight;
+                    false
+                }
+            })
+            .unwrap();
+        ordered.push(idxs.remove(pos));
+    }
+
+    ordered.extend(idxs);
+    ordered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_orders_by_weight() {
+        let weights = [1, 0, 3];
+        // 0 picks the first one with some weight, 3 falls in the weight of the last one
+        let mut randoms = vec![3, 0].into_iter();
+        let order = weighted_order(vec![0, 1, 2], &weights, &mut || randoms.next().unwrap());
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,275 @@
+// Local authorization cache.
+//
+// Successful authorizations are kept per (service, application) in proxy-wasm
+// shared data so that all workers can authorize from them, while the usages
+// of requests authorized from the cache are sent to the report queue.
+//
+// Denials are not kept, since any made up credentials would take an entry that
+// shared data cannot free. They just empty the entry of the application, if
+// it has one.
+//
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use threescalers::application::Application;
+
+const AUTHORIZATION_PREFIX: &str = "3scale.cache.authorized.";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("proxy failed to access shared data: {0:?}")]
+    SharedData(Status),
+    #[error("failed to (de)serialize shared data: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+// An owned and serializable version of the application credentials.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum App {
+    UserKey(String),
+    AppId(String, Option<String>),
+    AccessToken(String),
+}
+
+impl From<&Application> for App {
+    fn from(app: &Application) -> Self {
+        match app {
+            Application::UserKey(user_key) => Self::UserKey(user_key.as_ref().to_string()),
+            Application::AppId(app_id, app_key) => Self::AppId(
+                app_id.as_ref().to_string(),
+                app_key.as_ref().map(|key| key.as_ref().to_string()),
+            ),
+            Application::OAuthToken(token) => Self::AccessToken(token.as_ref().to_string()),
+        }
+    }
+}
+
+impl From<&App> for Application {
+    fn from(app: &App) -> Self {
+        match app {
+            App::UserKey(user_key) => Application::UserKey(user_key.clone().into()),
+            App::AppId(app_id, app_key) => {
+                Application::AppId(app_id.clone().into(), app_key.clone().map(Into::into))
+            }
+            App::AccessToken(token) => Application::OAuthToken(token.clone().into()),
+        }
+    }
+}
+
+impl App {
+    // Credentials are hashed so that keys have a bounded size.
+    fn key(&self, service_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(service_id.as_bytes());
+        match self {
+            Self::UserKey(user_key) => {
+                hasher.update(b"\0user_key\0");
+                hasher.update(user_key.as_bytes());
+            }
+            Self::AppId(app_id, app_key) => {
+                hasher.update(b"\0app_id\0");
+                hasher.update(app_id.as_bytes());
+                if let Some(app_key) = app_key {
+                    hasher.update(b"\0app_key\0");
+                    hasher.update(app_key.as_bytes());
+                }
+            }
+            Self::AccessToken(token) => {
+                hasher.update(b"\0access_token\0");
+                hasher.update(token.as_bytes());
+            }
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    expires_at: u64,
+}
+
+impl Entry {
+    fn new(now: u64, ttl: core::time::Duration) -> Self {
+        Self {
+            expires_at: now.saturating_add(ttl.as_secs()),
+        }
+    }
+
+    // Emptied entries and those failing to deserialize are misses.
+    fn decode(data: Option<&[u8]>) -> Option<Self> {
+        data.filter(|data| !data.is_empty())
+            .and_then(|data| serde_json::from_slice(data).ok())
+    }
+
+    fn is_fresh(&self, now: u64) -> bool {
+        self.expires_at > now
+    }
+}
+
+// What the cache knows about the authorization of an application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // authorized within the ttl
+    Authorized,
+    // denied since it was last authorized
+    Denied,
+    Unknown,
+}
+
+impl Outcome {
+    fn decode(data: Option<&[u8]>, now: u64) -> Self {
+        match data {
+            Some([]) => Self::Denied,
+            data => match Entry::decode(data) {
+                Some(entry) if entry.is_fresh(now) => Self::Authorized,
+                _ => Self::Unknown,
+            },
+        }
+    }
+}
+
+pub fn now<C: Context>(ctx: &C) -> u64 {
+    ctx.get_current_time()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn key(service_id: &str, app: &App) -> String {
+    format!("{}{}", AUTHORIZATION_PREFIX, app.key(service_id))
+}
+
+fn lookup_entry<C: Context>(ctx: &C, service_id: &str, app: &App) -> Option<Entry> {
+    let (data, _cas) = ctx.get_shared_data(key(service_id, app).as_str());
+    Entry::decode(data.as_deref())
+}
+
+pub fn outcome<C: Context>(ctx: &C, service_id: &str, app: &App) -> Outcome {
+    let (data, _cas) = ctx.get_shared_data(key(service_id, app).as_str());
+    Outcome::decode(data.as_deref(), now(ctx))
+}
+
+// The first application authorized from the cache, as long as those before it
+// are known to be denied, so that it is the one backend would authorize.
+pub fn first_authorized<T>(candidates: impl IntoIterator<Item = (T, Outcome)>) -> Option<T> {
+    for (app, outcome) in candidates {
+        match outcome {
+            Outcome::Authorized => return Some(app),
+            Outcome::Denied => continue,
+            Outcome::Unknown => return None,
+        }
+    }
+    None
+}
+
+// Whether the last known outcome was an authorization, even if it expired.
+pub fn was_authorized<C: Context>(ctx: &C, service_id: &str, app: &App) -> bool {
+    lookup_entry(ctx, service_id, app).is_some()
+}
+
+pub fn store_authorized<C: Context>(
+    ctx: &C,
+    service_id: &str,
+    app: &App,
+    ttl: core::time::Duration,
+) -> Result<(), Error> {
+    let data = serde_json::to_vec(&Entry::new(now(ctx), ttl))?;
+
+    // last writer wins, all of them are recent outcomes
+    ctx.set_shared_data(key(service_id, app).as_str(), Some(data.as_slice()), None)
+        .map_err(Error::SharedData)
+}
+
+// Forgets the application was authorized, if it was.
+pub fn invalidate<C: Context>(ctx: &C, service_id: &str, app: &App) -> Result<(), Error> {
+    let key = key(service_id, app);
+    match ctx.get_shared_data(key.as_str()) {
+        (Some(data), _) if !data.is_empty() => ctx
+            .set_shared_data(key.as_str(), Some(&[]), None)
+            .map_err(Error::SharedData),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_keys_entries_by_service_and_credentials() {
+        let app = App::AppId("app".into(), Some("secret".into()));
+        assert_eq!(key("svc", &app), key("svc", &app.clone()));
+        assert_ne!(key("svc", &app), key("other", &app));
+        assert_ne!(
+            key("svc", &app),
+            key("svc", &App::AppId("app".into(), None))
+        );
+        assert_ne!(
+            key("svc", &App::UserKey("app".into())),
+            key("svc", &App::AccessToken("app".into()))
+        );
+
+        let key = key("svc", &App::UserKey("k".repeat(4096)));
+        assert!(key.starts_with(AUTHORIZATION_PREFIX));
+        assert_eq!(key.len(), AUTHORIZATION_PREFIX.len() + 64);
+    }
+
+    #[test]
+    fn it_expires_entries_after_ttl() {
+        let entry = Entry::new(1000, core::time::Duration::from_secs(60));
+        assert!(entry.is_fresh(1000));
+        assert!(entry.is_fresh(1059));
+        assert!(!entry.is_fresh(1060));
+    }
+
+    #[test]
+    fn it_tells_outcomes_apart() {
+        let data =
+            serde_json::to_vec(&Entry::new(1000, core::time::Duration::from_secs(60))).unwrap();
+        assert_eq!(
+            Outcome::decode(Some(data.as_slice()), 1000),
+            Outcome::Authorized
+        );
+        assert_eq!(
+            Outcome::decode(Some(data.as_slice()), 1060),
+            Outcome::Unknown
+        );
+        assert_eq!(Outcome::decode(Some(&[]), 1000), Outcome::Denied);
+        assert_eq!(Outcome::decode(None, 1000), Outcome::Unknown);
+    }
+
+    #[test]
+    fn it_authorizes_the_app_backend_would() {
+        use Outcome::*;
+
+        assert_eq!(
+            first_authorized(vec![(1, Denied), (2, Authorized)]),
+            Some(2)
+        );
+        assert_eq!(
+            first_authorized(vec![(1, Authorized), (2, Authorized)]),
+            Some(1)
+        );
+        // the first app might be authorized by backend
+        assert_eq!(first_authorized(vec![(1, Unknown), (2, Authorized)]), None);
+        assert_eq!(
+            first_authorized(vec![(1, Denied), (2, Denied)]),
+            None::<i32>
+        );
+    }
+
+    #[test]
+    fn it_decodes_hits_and_misses() {
+        let entry = Entry::new(1000, core::time::Duration::from_secs(60));
+        let data = serde_json::to_vec(&entry).unwrap();
+        assert_eq!(Entry::decode(Some(data.as_slice())), Some(entry));
+
+        assert_eq!(Entry::decode(None), None);
+        // invalidated
+        assert_eq!(Entry::decode(Some(&[])), None);
+        assert_eq!(Entry::decode(Some(b"{\"authorization\"")), None);
+    }
+}
@@ -1,20 +1,130 @@
+use std::collections::BTreeMap;
+
 use proxy_wasm::traits::{Context, HttpContext};
-use proxy_wasm::types::FilterHeadersStatus;
-use threescalers::application::Application;
+use proxy_wasm::types::{FilterDataStatus, FilterHeadersStatus};
+use threescalers::{application::Application, http::Request};
 
-use crate::configuration::Configuration;
+use crate::configuration::{render_template, safe_request_id, Configuration, FailureClass};
 use crate::log::IdentLogger;
-use crate::threescale::CredentialsError;
+use crate::threescale::{CredentialsError, Mode, OnBackendError, ResponseHeader, Service};
+use crate::util::rand::thread_rng::ThreadRng;
 
+use super::app_info::{self, AppInfo};
 use super::authrep;
 use super::authrep::MatchError;
+use super::backend_health::SharedHealth;
+use super::cache;
+use super::rate_limit::RateLimit;
+use super::rejection::{self, Rejection};
+use super::report_queue::{PendingUsage, Record, ReportQueue};
 use super::request_headers::RequestHeaders;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyBuffering {
+    // no credentials are looked up in the request body
+    Disabled,
+    // buffering up to the given amount of bytes to look up credentials
+    Pending(usize),
+    // credentials looked up, waiting for the authorization decision
+    Done,
+}
+
+// How much of the request body is buffered when waiting for all of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyProgress {
+    Partial,
+    Complete,
+    // too large to look up credentials in it
+    OverLimit,
+}
+
+impl BodyProgress {
+    pub fn new(limit: usize, body_size: usize, end_of_stream: bool) -> Self {
+        if body_size > limit {
+            Self::OverLimit
+        } else if end_of_stream {
+            Self::Complete
+        } else {
+            Self::Partial
+        }
+    }
+}
+
+// The authorization in flight, kept to act on the backend's response.
+pub struct InFlight {
+    pub service_id: String,
+    pub app: cache::App,
+    pub usages: BTreeMap<String, i64>,
+    // deltas to compute from the upstream response for deferred reports
+    pub response_usages: Vec<(String, ResponseHeader)>,
+    pub mode: Mode,
+    // sent along deferred reports if the service asks for logs
+    pub request_log: Option<String>,
+}
+
+// Failures to get an answer from backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendError {
+    Dispatch,
+    Timeout,
+    ServerError(u32),
+    Malformed,
+}
+
+impl BackendError {
+    // Returns the status of a backend response that answers the call, that
+    // is, authorizations (200) and rejections (4xx), or the error otherwise.
+    pub fn check_status(status: Option<u32>) -> Result<u32, Self> {
+        match status {
+            None => Err(Self::Malformed),
+            Some(code) if code >= 500 => Err(Self::ServerError(code)),
+            Some(code) if code == 200 || (400..500).contains(&code) => Ok(code),
+            Some(_) => Err(Self::Malformed),
+        }
+    }
+
+    pub fn class(&self) -> FailureClass {
+        FailureClass::BackendUnavailable
+    }
+
+    // The default response when the request is not let through.
+    pub fn response(&self) -> (u32, &'static str) {
+        match self {
+            Self::Dispatch => (403, "Authentication failed\n"),
+            Self::Timeout | Self::ServerError(_) | Self::Malformed => (502, "Bad Gateway\n"),
+        }
+    }
+}
+
+// A call to backend, kept to retry it on other upstreams should it fail.
+pub struct BackendCall {
+    path: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    // upstream the call was dispatched to
+    upstream: usize,
+    // upstreams left to try, in order
+    fallbacks: Vec<usize>,
+}
+
 pub struct HttpAuthThreescale {
     pub configuration: Configuration,
     pub context_id: u32,
     pub id: u32,
     pub log_id: String,
+    pub body_buffering: BodyBuffering,
+    pub request_body: Option<Vec<u8>>,
+    // authrep calls left to try should the current one not authorize
+    pub pending_authreps: Vec<(cache::App, Request)>,
+    pub current_authrep: Option<InFlight>,
+    // authorized request to report once upstream responds
+    pub deferred_report: Option<InFlight>,
+    // limits backend reported for the application, if any
+    pub rate_limit: Option<RateLimit>,
+    pub report_queue: Option<ReportQueue>,
+    pub backend_call: Option<BackendCall>,
+    pub backend_health: SharedHealth,
 }
 
 impl IdentLogger for HttpAuthThreescale {
@@ -28,23 +138,28 @@ impl HttpAuthThreescale {
     pub fn configuration(&self) -> &crate::configuration::api::v1::Configuration {
         self.configuration.get()
     }
-}
 
-impl HttpContext for HttpAuthThreescale {
-    fn on_http_request_headers(&mut self, _: usize) -> FilterHeadersStatus {
-        info!(
-            self,
-            "on_http_request_headers: context_id {}", self.context_id
-        );
+    pub fn request_body(&self) -> Option<&[u8]> {
+        self.request_body.as_deref()
+    }
 
-        //let backend = match self.configuration.get_backend() {
-        //    Err(e) => {
-        //        error!("error obtaining configuration for 3scale backend: {:?}", e);
-        //        return FilterHeadersStatus::Continue;
-        //    }
-        //    Ok(backend) => backend,
-        //};
+    // Returns the amount of body to buffer if the request has a body and the
+    // matching service looks up credentials or usage deltas in it.
+    fn request_body_limit(&self) -> Option<usize> {
+        let rh = RequestHeaders::new(self);
+        if !rh.has_body() {
+            return None;
+        }
+
+        let url = rh.url().ok()?;
+        authrep::match_service(self, &rh, &url)
+            .ok()?
+            .body_limit(&rh)
+    }
 
+    // Returns whether the request can continue right away. Otherwise either a
+    // response has been sent or we are waiting for the backend to respond.
+    fn process_request(&mut self) -> bool {
         let backend = self.configuration().get_backend().ok();
 
         let rh = RequestHeaders::new(self);
@@ -52,22 +167,86 @@ impl HttpContext for HttpAuthThreescale {
         let ar = match authrep::authrep(self, &rh) {
             Err(e) => {
                 error!(self, "error computing authrep {:?}", e);
+                // the service, if known, might customize the response
+                let service_id = rh
+                    .url()
+                    .ok()
+                    .and_then(|url| authrep::match_service(self, &rh, &url).ok())
+                    .map(|svc| svc.id().to_string());
+                let service_id = service_id.as_deref();
                 match e.downcast_ref::<MatchError>() {
-                    Some(MatchError::NoServiceMatched) => {
-                        self.send_http_response(403, vec![], Some(b"Unknown service\n"))
+                    Some(MatchError::NoServiceMatched) => self.send_error_response(
+                        FailureClass::UnknownService,
+                        service_id,
+                        403,
+                        vec![],
+                        "Unknown service\n",
+                    ),
+                    Some(MatchError::NoUsageMatch) => self.send_error_response(
+                        FailureClass::NoMappingRule,
+                        service_id,
+                        404,
+                        vec![],
+                        "No Mapping Rule matched\n",
+                    ),
+                    Some(MatchError::CredentialsError(CredentialsError::Oidc(e)))
+                        if e.is_unauthenticated() =>
+                    {
+                        self.send_error_response(
+                            FailureClass::InvalidCredentials,
+                            service_id,
+                            401,
+                            vec![("WWW-Authenticate", r#"Bearer error="invalid_token""#)],
+                            "Invalid token\n",
+                        )
                     }
-                    Some(MatchError::NoUsageMatch) => {
-                        self.send_http_response(404, vec![], Some(b"No Mapping Rule matched\n"))
+                    Some(MatchError::CredentialsError(CredentialsError::Oidc(_))) => self
+                        .send_error_response(
+                            FailureClass::InvalidCredentials,
+                            service_id,
+                            403,
+                            vec![],
+                            "Token not accepted\n",
+                        ),
+                    Some(MatchError::CredentialsError(CredentialsError::BasicAuthRequired(
+                        realm,
+                    ))) => {
+                        let challenge = format!(r#"Basic realm="{}""#, realm.replace('"', "'"));
+                        self.send_error_response(
+                            FailureClass::MissingCredentials,
+                            service_id,
+                            401,
+                            vec![("WWW-Authenticate", challenge.as_str())],
+                            "Authentication required\n",
+                        )
                     }
-                    Some(MatchError::CredentialsError(_)) => self.send_http_response(
+                    Some(MatchError::CredentialsError(CredentialsError::Ambiguous)) => self
+                        .send_error_response(
+                            FailureClass::InvalidCredentials,
+                            service_id,
+                            400,
+                            vec![],
+                            "Ambiguous credentials\n",
+                        ),
+                    Some(MatchError::CredentialsError(CredentialsError::Conflicting(_))) => self
+                        .send_error_response(
+                            FailureClass::InvalidCredentials,
+                            service_id,
+                            400,
+                            vec![],
+                            "Conflicting credentials\n",
+                        ),
+                    Some(MatchError::CredentialsError(_)) => self.send_error_response(
+                        FailureClass::MissingCredentials,
+                        service_id,
                         403,
                         vec![],
-                        Some(b"Authentication parameters missing\n"),
+                        "Authentication parameters missing\n",
                     ),
                     _ => self.send_http_response(403, vec![], Some(b"Authentication failed\n")),
                 };
-                debug!(self, "403 sent");
-                return FilterHeadersStatus::StopIteration;
+                debug!(self, "error response sent");
+                return false;
             }
             Ok(params) => params,
         };
@@ -76,101 +255,780 @@ impl HttpContext for HttpAuthThreescale {
 
         if passthrough_metadata {
             match self.threescale_info_to_metadata(&ar) {
-                Ok(()) => return FilterHeadersStatus::Continue,
+                Ok(()) => return true,
                 Err(e) => {
                     error!(self, "failed to pass app info to next filter: {:?}", e);
                     self.send_http_response(403, vec![], Some(b"Authentication failed\n"));
                     debug!(self, "403 sent");
-                    return FilterHeadersStatus::StopIteration;
+                    return false;
                 }
             }
         }
 
-        if let Some(backend) = backend {
-            let request = match authrep::build_call(&ar) {
-                Err(e) => {
-                    error!(self, "error computing authrep request {:?}", e);
-                    let message = match e.downcast_ref::<CredentialsError>() {
-                        Some(CredentialsError::NotFound) => "Authentication parameters missing\n",
-                        _ => "Authentication failed\n",
-                    };
-                    self.send_http_response(403, vec![], Some(message.as_bytes()));
-                    debug!(self, "403 sent");
-                    return FilterHeadersStatus::StopIteration;
+        if backend.is_none() {
+            // no backend configured
+            debug!(self, "process_request: no backend configured");
+            self.send_error_response(
+                FailureClass::BackendUnavailable,
+                Some(ar.service().id()),
+                403,
+                vec![],
+                "Authentication failed\n",
+            );
+            debug!(self, "403 sent");
+            return false;
+        }
+
+        let mode = ar.mode();
+        let try_all = ar.service().credentials_policy().try_all();
+
+        let request_log = match (mode, ar.service().report_log()) {
+            (Mode::DeferredReport, Some(report_log)) => {
+                let metadata = rh.metadata();
+                let log = format!("{} {}", metadata.method(), metadata.path());
+                Some(report_log.truncate(log.as_str()).to_string())
+            }
+            _ => None,
+        };
+
+        let in_flight = |app: cache::App| InFlight {
+            service_id: ar.service().id().to_string(),
+            app,
+            usages: ar
+                .usages()
+                .iter()
+                .map(|(&metric, &delta)| (metric.to_string(), delta))
+                .collect(),
+            response_usages: ar
+                .response_usages()
+                .iter()
+                .map(|&(metric, header)| (metric.to_string(), header.clone()))
+                .collect(),
+            mode,
+            request_log: request_log.clone(),
+        };
+
+        let extensions = backend.and_then(|backend| backend.extensions());
+
+        if mode == Mode::Report {
+            // the request goes on without waiting for backend
+            let mut deferred_report = None;
+            if let Some(app) = ar.apps().first() {
+                let in_flight = in_flight(cache::App::from(app));
+                if !in_flight.response_usages.is_empty()
+                    || ar.service().report_condition().is_some()
+                {
+                    // response deltas and report conditions are only known
+                    // once upstream responds
+                    deferred_report = Some(in_flight);
+                } else if self.report_queue.is_some() {
+                    self.enqueue_usages(&in_flight);
+                } else if let Err(e) = authrep::build_call(&ar, extensions)
+                    .and_then(|request| self.dispatch_authrep(&request))
+                {
+                    error!(self, "process_request: failed to report usages: {}", e);
+                }
+            }
+            let info = ar
+                .apps()
+                .first()
+                .map(|app| AppInfo::new(&cache::App::from(app)))
+                .unwrap_or_default();
+            self.prepare_upstream_request(ar.service(), &info);
+            self.deferred_report = deferred_report;
+            return true;
+        }
+
+        if self.cache_ttl().is_some() {
+            // the same applications backend would be asked about, in order
+            let tried = if try_all { ar.apps().len() } else { 1 };
+            let candidates = ar.apps().iter().take(tried).map(|app| {
+                let app = cache::App::from(app);
+                let outcome = cache::outcome(self, ar.service().id(), &app);
+                (app, outcome)
+            });
+            if let Some(app) = cache::first_authorized(candidates) {
+                debug!(self, "process_request: authorized from cache");
+                self.prepare_upstream_request(ar.service(), &AppInfo::new(&app));
+                if mode == Mode::DeferredReport {
+                    self.deferred_report = Some(in_flight(app));
+                } else {
+                    self.enqueue_usages(&in_flight(app));
+                }
+                return true;
+            }
+        }
+
+        let requests = if try_all {
+            authrep::build_calls(&ar, extensions)
+        } else {
+            authrep::build_call(&ar, extensions).map(|request| vec![request])
+        };
+
+        let mut requests = match requests {
+            Err(e) => {
+                error!(self, "error computing authrep request {:?}", e);
+                match e.downcast_ref::<CredentialsError>() {
+                    Some(CredentialsError::NotFound) => self.send_error_response(
+                        FailureClass::MissingCredentials,
+                        Some(ar.service().id()),
+                        403,
+                        vec![],
+                        "Authentication parameters missing\n",
+                    ),
+                    _ => self.send_http_response(403, vec![], Some(b"Authentication failed\n")),
                 }
-                Ok(request) => request,
+                debug!(self, "403 sent");
+                return false;
+            }
+            Ok(requests) => ar
+                .apps()
+                .iter()
+                .map(cache::App::from)
+                .zip(requests)
+                .collect::<Vec<_>>()
+                .into_iter(),
+        };
+
+        // build_call(s) fail rather than return no requests
+        let (app, request) = requests.next().unwrap();
+        let current_authrep = in_flight(app);
+        self.pending_authreps = requests.collect();
+        self.current_authrep = Some(current_authrep);
+
+        match self.dispatch_authrep(&request) {
+            Ok(call) => {
+                self.backend_call = Some(call);
+                false
+            }
+            Err(e) => {
+                error!(self, "process_request: {}", e);
+                if self.allow_on_backend_error(BackendError::Dispatch) {
+                    self.let_authorized_through(None);
+                    return true;
+                }
+                self.send_backend_unavailable(BackendError::Dispatch);
+                false
+            }
+        }
+    }
+
+    // Decides whether to let the request in flight through when backend
+    // cannot be reached, as per the service's on_backend_error policy.
+    fn allow_on_backend_error(&self, error: BackendError) -> bool {
+        let in_flight = match self.current_authrep.as_ref() {
+            Some(in_flight) => in_flight,
+            None => return false,
+        };
+
+        let policy = self
+            .configuration()
+            .services()
+            .and_then(|services| services.iter().find(|svc| svc.id() == in_flight.service_id))
+            .map(|svc| svc.on_backend_error())
+            .unwrap_or_default();
+
+        let allow = match policy {
+            OnBackendError::Deny => false,
+            OnBackendError::Allow => true,
+            // the last known outcome, no matter how old
+            OnBackendError::AllowWithCache => {
+                cache::was_authorized(self, in_flight.service_id.as_str(), &in_flight.app)
+            }
+        };
+
+        warn!(
+            self,
+            "backend error {:?}, policy {:?}: {}",
+            error,
+            policy,
+            if allow { "allowing" } else { "denying" }
+        );
+
+        if allow {
+            // report the usages once backend is back, if possible
+            self.enqueue_usages(in_flight);
+        }
+
+        allow
+    }
+
+    fn cache_ttl(&self) -> Option<core::time::Duration> {
+        self.configuration()
+            .backend()
+            .and_then(|backend| backend.cache())
+            .map(|cache| cache.ttl())
+    }
+
+    // Keeps the backend's authorization outcome for the application in flight.
+    fn cache_authorization(&self, authorized: bool) {
+        if let (Some(ttl), Some(in_flight)) = (self.cache_ttl(), &self.current_authrep) {
+            let service_id = in_flight.service_id.as_str();
+            let result = if authorized {
+                cache::store_authorized(self, service_id, &in_flight.app, ttl)
+            } else {
+                cache::invalidate(self, service_id, &in_flight.app)
             };
+            if let Err(e) = result {
+                error!(self, "failed to cache authorization: {}", e);
+            }
+        }
+    }
+
+    fn enqueue_usages(&self, in_flight: &InFlight) {
+        match in_flight.mode {
+            Mode::Authorize => {
+                debug!(self, "authorize mode, not reporting usages");
+                return;
+            }
+            // reported once upstream responds
+            Mode::DeferredReport => return,
+            _ => (),
+        }
+
+        let queue = match self.report_queue {
+            Some(queue) => queue,
+            None => {
+                error!(self, "no usage report queue, usages will not be reported");
+                return;
+            }
+        };
+
+        let record = Record {
+            service_id: in_flight.service_id.clone(),
+            app: in_flight.app.clone(),
+            usages: in_flight.usages.clone(),
+            timestamp: cache::now(self),
+        };
+
+        match queue.enqueue(self, &record) {
+            Ok(true) => (),
+            Ok(false) => warn!(self, "usage report queue full, dropping usages"),
+            Err(e) => error!(self, "failed to enqueue usages: {}", e),
+        }
+    }
+
+    // Sends the response configured for the failure class, if any, or the
+    // given default one otherwise.
+    fn send_error_response(
+        &self,
+        class: FailureClass,
+        service_id: Option<&str>,
+        status: u32,
+        headers: Vec<(&str, &str)>,
+        body: &str,
+    ) {
+        let config = self.configuration();
+        let service = service_id.and_then(|id| {
+            config
+                .services()
+                .and_then(|services| services.iter().find(|svc| svc.id() == id))
+        });
+        // the service's responses win over global ones, and within each table
+        // the class wins over its fallback
+        let configured = service
+            .map(|svc| svc.error_responses())
+            .into_iter()
+            .chain(config.error_responses())
+            .find_map(|responses| {
+                std::iter::once(class)
+                    .chain(class.fallback())
+                    .find_map(|class| responses.get(&class))
+            });
+
+        let response = match configured {
+            Some(response) => response,
+            None => {
+                self.send_http_response(status, headers, Some(body.as_bytes()));
+                return;
+            }
+        };
+
+        let status = response.status.unwrap_or(status);
+        let body = match response.body.as_deref() {
+            Some(template) => {
+                let request_id = self
+                    .get_http_request_header("x-request-id")
+                    .unwrap_or_default();
+                let status = status.to_string();
+                render_template(
+                    template,
+                    &[
+                        (
+                            "request_id",
+                            safe_request_id(request_id.as_str()).unwrap_or_default(),
+                        ),
+                        ("service_id", service_id.unwrap_or_default()),
+                        ("status", status.as_str()),
+                    ],
+                )
+            }
+            None => body.to_string(),
+        };
+
+        // configured headers replace the default ones
+        let overridden = |name: &str| {
+            response
+                .headers
+                .keys()
+                .any(|h| h.eq_ignore_ascii_case(name))
+                || (response.content_type.is_some() && name.eq_ignore_ascii_case("content-type"))
+        };
+        let mut headers = headers
+            .into_iter()
+            .filter(|(name, _)| !overridden(name))
+            .collect::<Vec<_>>();
+        headers.extend(
+            response
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        if let Some(content_type) = response.content_type.as_deref() {
+            headers.push(("Content-Type", content_type));
+        }
+
+        debug!(self, "sending configured {:?} response", class);
+        self.send_http_response(status, headers, Some(body.as_bytes()));
+    }
+
+    // Responds when backend cannot be relied on for the request in flight.
+    fn send_backend_unavailable(&self, error: BackendError) {
+        let service_id = self
+            .current_authrep
+            .as_ref()
+            .map(|in_flight| in_flight.service_id.as_str());
+        if let Err(e) = rejection::record_backend_unavailable() {
+            debug!(self, "failed to record backend unavailable metric: {:?}", e);
+        }
+
+        let (status, body) = error.response();
+        self.send_error_response(error.class(), service_id, status, vec![], body);
+    }
+
+    fn send_rejection(&self, service_id: &str, reason: &str) {
+        let rejection = Rejection::from(reason);
+        if rejection.is_client_error() {
+            info!(
+                self,
+                "rejected ({}): {}",
+                rejection.as_str(),
+                rejection.description()
+            );
+        } else {
+            error!(
+                self,
+                "rejected ({}): {}, check the configuration of service {}",
+                rejection.as_str(),
+                rejection.description(),
+                service_id
+            );
+        }
+        if let Err(e) = rejection.record() {
+            debug!(self, "failed to record rejection metric: {:?}", e);
+        }
 
-            // uri will actually just get the whole path + parameters
-            let (uri, body) = request.uri_and_body();
+        let mut headers = self.rate_limit.map(|rl| rl.headers()).unwrap_or_default();
+        if rejection == Rejection::LimitsExceeded {
+            headers.extend(self.rate_limit.and_then(|rl| rl.retry_after()));
+        }
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+
+        let (status, body) = rejection.response();
+        self.send_error_response(rejection.class(), Some(service_id), status, headers, body);
+    }
+
+    fn dispatch_authrep(&self, request: &Request) -> Result<BackendCall, anyhow::Error> {
+        self.dispatch_call(request, None)
+    }
+
+    // Dispatches a call to backend, appending the given url-encoded
+    // parameters to those of the request.
+    fn dispatch_call(
+        &self,
+        request: &Request,
+        extra_params: Option<&str>,
+    ) -> Result<BackendCall, anyhow::Error> {
+        let backend = self.configuration().get_backend()?;
+        let fallbacks =
+            self.backend_health
+                .borrow_mut()
+                .candidates(backend, self.get_current_time(), || ThreadRng.next_u32());
+
+        // uri will actually just get the whole path + parameters
+        let (uri, body) = request.uri_and_body();
+        let mut uri = uri.into_owned();
+        let mut body = body.map(str::to_string);
+        if let Some(params) = extra_params.filter(|params| !params.is_empty()) {
+            match body.as_mut() {
+                Some(body) => {
+                    if !body.is_empty() {
+                        body.push('&');
+                    }
+                    body.push_str(params);
+                }
+                None => {
+                    uri.push(if uri.contains('?') { '&' } else { '?' });
+                    uri.push_str(params);
+                }
+            }
+        }
 
-            let headers = request
+        let call = BackendCall {
+            path: uri,
+            method: request.method.as_str().to_string(),
+            headers: request
+                .headers
+                .iter()
+                .map(|(key, value)| (key.as_str().to_string(), value.as_str().to_string()))
+                .collect(),
+            body,
+            upstream: 0,
+            fallbacks,
+        };
+
+        self.dispatch_backend_call(call)
+    }
+
+    // Dispatches the call to the first of its fallbacks accepting it.
+    fn dispatch_backend_call(&self, mut call: BackendCall) -> Result<BackendCall, anyhow::Error> {
+        let backend = self.configuration().get_backend()?;
+        let upstreams = backend.upstreams();
+
+        while !call.fallbacks.is_empty() {
+            let idx = call.fallbacks.remove(0);
+            let upstream = match upstreams.get(idx) {
+                Some(&(upstream, _)) => upstream,
+                None => continue,
+            };
+
+            let headers = call
                 .headers
                 .iter()
                 .map(|(key, value)| (key.as_str(), value.as_str()))
                 .collect::<Vec<_>>();
 
-            let upstream = backend.upstream();
-            let call_token = match upstream.call(
+            match upstream.call(
                 self,
-                uri.as_ref(),
-                request.method.as_str(),
+                call.path.as_str(),
+                call.method.as_str(),
                 headers,
                 None,
-                body.map(str::as_bytes),
+                call.body.as_deref().map(str::as_bytes),
                 None,
                 None,
             ) {
-                Ok(call_token) => call_token,
+                Ok(call_token) => {
+                    info!(
+                        self,
+                        "dispatch_call: call token is {} for {}",
+                        call_token,
+                        upstream.name()
+                    );
+                    call.upstream = idx;
+                    return Ok(call);
+                }
                 Err(e) => {
-                    error!(self, "on_http_request_headers: could not dispatch HTTP call to {}: did you create the cluster to do so? - {:#?}", upstream.name(), e);
-                    self.send_http_response(403, vec![], Some(b"Authentication failed\n"));
-                    debug!(self, "403 sent");
-                    return FilterHeadersStatus::StopIteration;
+                    warn!(
+                        self,
+                        "could not dispatch HTTP call to {}: did you create the cluster to do so? - {:#?}",
+                        upstream.name(),
+                        e
+                    );
+                    self.record_backend_failure(idx);
                 }
-            };
+            }
+        }
 
-            info!(
+        anyhow::bail!("could not dispatch HTTP call to any backend upstream")
+    }
+
+    fn record_backend_success(&self, idx: usize) {
+        self.backend_health.borrow_mut().record_success(idx);
+    }
+
+    fn record_backend_failure(&self, idx: usize) {
+        let backend = match self.configuration().get_backend() {
+            Ok(backend) => backend,
+            Err(_) => return,
+        };
+
+        let now = self.get_current_time();
+        if self
+            .backend_health
+            .borrow_mut()
+            .record_failure(backend, idx, now)
+        {
+            let name = backend
+                .upstreams()
+                .get(idx)
+                .map_or("unknown", |(upstream, _)| upstream.name());
+            warn!(self, "backend upstream {} is unhealthy, avoiding it", name);
+        }
+    }
+
+    // Resumes the request waiting for backend, now authorized.
+    fn resume_authorized(&mut self, response_body: Option<Vec<u8>>) {
+        self.let_authorized_through(response_body);
+        self.resume_http_request();
+    }
+
+    // Gets the request in flight ready to go upstream, along with the data
+    // from the body of the authorization response, if any.
+    fn let_authorized_through(&mut self, response_body: Option<Vec<u8>>) {
+        if let Some(in_flight) = self.current_authrep.as_ref() {
+            let service = self
+                .configuration()
+                .services()
+                .and_then(|services| services.iter().find(|svc| svc.id() == in_flight.service_id));
+            if let Some(service) = service {
+                let mut info = AppInfo::new(&in_flight.app);
+                if let Some(body) = response_body.filter(|_| !service.upstream_headers().is_empty())
+                {
+                    if let Err(e) = info.parse_response(body.as_slice()) {
+                        warn!(
+                            self,
+                            "let_authorized_through: no upstream data taken from the authorization response: {}",
+                            e
+                        );
+                        if let Err(e) = app_info::record_parse_error() {
+                            debug!(self, "failed to record upstream data error metric: {:?}", e);
+                        }
+                    }
+                }
+                self.prepare_upstream_request(service, &info);
+            }
+        }
+        self.defer_report();
+        self.body_buffering = BodyBuffering::Disabled;
+    }
+
+    // Removes the credentials the service asks to strip from the request and
+    // sets the headers it asks for, replacing any sent by the client.
+    fn prepare_upstream_request(&self, service: &Service, info: &AppInfo) {
+        service.credentials().strip(self);
+        for (name, value) in info.headers(service.upstream_headers()) {
+            self.set_http_request_header(name, value.as_deref());
+        }
+    }
+
+    // Keeps the authorized request in flight to report it once upstream
+    // responds, if its mode asks for it.
+    fn defer_report(&mut self) {
+        if let Some(InFlight {
+            mode: Mode::DeferredReport,
+            ..
+        }) = self.current_authrep
+        {
+            self.deferred_report = self.current_authrep.take();
+        }
+    }
+
+    // Reports the usages of the request along with the upstream response.
+    fn send_deferred_report(&self, report: InFlight) -> Result<(), anyhow::Error> {
+        let service = self
+            .configuration()
+            .get_services()?
+            .iter()
+            .find(|svc| svc.id() == report.service_id)
+            .ok_or_else(|| anyhow::anyhow!("service {} not found", report.service_id))?;
+
+        let code = self
+            .get_http_response_header(":status")
+            .and_then(|code| code.parse::<u32>().ok());
+        if let Some(condition) = service.report_condition() {
+            if !condition.is_satisfied(self, code) {
+                debug!(
+                    self,
+                    "send_deferred_report: report condition not met for status {:?}", code
+                );
+                return Ok(());
+            }
+        }
+        let response_log = service.report_log().map(|report_log| {
+            let log = authrep::response_log(&self.get_http_response_headers());
+            report_log.truncate(log.as_str()).to_string()
+        });
+
+        let mut usages = report.usages;
+        for (metric, header) in report.response_usages.iter() {
+            let delta = header.resolve(self).unwrap_or_else(|| {
+                debug!(
+                    self,
+                    "send_deferred_report: no delta found for {}, using 0", metric
+                );
+                0
+            });
+            *usages.entry(metric.clone()).or_insert(0) += delta;
+        }
+
+        let pending = PendingUsage {
+            app: report.app,
+            usages,
+            oldest: cache::now(self),
+        };
+        let request = authrep::build_report(service, std::slice::from_ref(&pending))?;
+        let log = authrep::transaction_log_params(
+            code,
+            report.request_log.as_deref(),
+            response_log.as_deref(),
+        );
+
+        // reports are not retried on other upstreams once dispatched
+        self.dispatch_call(&request, Some(log.as_str())).map(|_| ())
+    }
+}
+
+impl HttpContext for HttpAuthThreescale {
+    fn on_http_request_headers(&mut self, _: usize) -> FilterHeadersStatus {
+        info!(
+            self,
+            "on_http_request_headers: context_id {}", self.context_id
+        );
+
+        if let Some(limit) = self.request_body_limit() {
+            debug!(
                 self,
-                "on_http_request_headers: call token is {}", call_token
+                "on_http_request_headers: waiting for up to {} bytes of request body", limit
             );
+            self.body_buffering = BodyBuffering::Pending(limit);
+            return FilterHeadersStatus::StopIteration;
+        }
 
-            FilterHeadersStatus::StopIteration
+        if self.process_request() {
+            FilterHeadersStatus::Continue
         } else {
-            // no backend configured
-            debug!(self, "on_http_request_headers: no backend configured");
-            self.send_http_response(403, vec![], Some(b"Authentication failed\n"));
-            debug!(self, "403 sent");
             FilterHeadersStatus::StopIteration
         }
     }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> FilterDataStatus {
+        match self.body_buffering {
+            BodyBuffering::Disabled => FilterDataStatus::Continue,
+            // hold the body until the authorization decision is made
+            BodyBuffering::Done => FilterDataStatus::StopIterationAndBuffer,
+            BodyBuffering::Pending(limit) => {
+                match BodyProgress::new(limit, body_size, end_of_stream) {
+                    BodyProgress::Partial => return FilterDataStatus::StopIterationAndBuffer,
+                    BodyProgress::OverLimit => warn!(
+                        self,
+                        "on_http_request_body: body size {} exceeds limit of {} bytes, not looking up credentials in it",
+                        body_size,
+                        limit
+                    ),
+                    BodyProgress::Complete => {
+                        self.request_body = self.get_http_request_body(0, body_size)
+                    }
+                }
+
+                self.body_buffering = BodyBuffering::Done;
+                if self.process_request() {
+                    self.body_buffering = BodyBuffering::Disabled;
+                    FilterDataStatus::Continue
+                } else {
+                    FilterDataStatus::StopIterationAndBuffer
+                }
+            }
+        }
+    }
+
     fn on_http_response_headers(&mut self, _: usize) -> FilterHeadersStatus {
         self.set_http_response_header("Powered-By", Some("3scale"));
+        if let Some(report) = self.deferred_report.take() {
+            if let Err(e) = self.send_deferred_report(report) {
+                error!(
+                    self,
+                    "on_http_response_headers: failed to report usages: {}", e
+                );
+            }
+        }
+        if let Some(rate_limit) = self.rate_limit {
+            for (name, value) in rate_limit.headers() {
+                self.set_http_response_header(name, Some(value.as_str()));
+            }
+        }
         FilterHeadersStatus::Continue
     }
 }
 
 impl Context for HttpAuthThreescale {
-    fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
+    fn on_http_call_response(&mut self, token_id: u32, _: usize, body_size: usize, _: usize) {
         info!(
             self,
             "http_ctx: on_http_call_response: token id is {}", token_id
         );
-        let status_code = match self
-            .get_http_call_response_headers()
-            .into_iter()
+        // report calls are not waited for
+        if self.current_authrep.is_none() {
+            debug!(
+                self,
+                "on_http_call_response: ignoring response to report {}", token_id
+            );
+            return;
+        }
+        let response_headers = self.get_http_call_response_headers();
+        let status_code = match response_headers
+            .iter()
             .find(|(key, _)| key.as_str() == ":status")
         {
+            // the call failed or timed out
             None => {
                 debug!(
                     self,
                     "on_http_call_response: empty status header {}", token_id
                 );
-                self.send_http_response(502, vec![], Some(b"Bad Gateway\n"));
+                if let Some(call) = self.backend_call.take() {
+                    self.record_backend_failure(call.upstream);
+                    if !call.fallbacks.is_empty() {
+                        match self.dispatch_backend_call(call) {
+                            Ok(call) => {
+                                info!(
+                                    self,
+                                    "on_http_call_response: retrying {} on next backend upstream",
+                                    token_id
+                                );
+                                self.backend_call = Some(call);
+                                return;
+                            }
+                            Err(e) => error!(self, "on_http_call_response: {}", e),
+                        }
+                    }
+                }
+                if self.allow_on_backend_error(BackendError::Timeout) {
+                    self.resume_authorized(None);
+                } else {
+                    self.send_backend_unavailable(BackendError::Timeout);
+                }
+                return;
+            }
+            Some((_, code)) => code.parse::<u32>().ok(),
+        };
+
+        if let Some(upstream) = self.backend_call.as_ref().map(|call| call.upstream) {
+            match status_code {
+                Some(code) if code < 500 => self.record_backend_success(upstream),
+                _ => self.record_backend_failure(upstream),
+            }
+        }
+
+        self.rate_limit = RateLimit::from_headers(
+            response_headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+
+        let status_code = match BackendError::check_status(status_code) {
+            Ok(code) => code,
+            Err(e) => {
+                if self.allow_on_backend_error(e) {
+                    self.resume_authorized(None);
+                } else {
+                    self.send_backend_unavailable(e);
+                }
                 return;
             }
-            Some((_, code)) => code.parse::<u32>().unwrap_or(500),
         };
 
         info!(
@@ -179,23 +1037,67 @@ impl Context for HttpAuthThreescale {
         );
         if status_code == 200 {
             info!(self, "on_http_call_response: authorized {}", token_id);
-            self.resume_http_request();
+            self.cache_authorization(true);
+            let body = if body_size > 0 {
+                self.get_http_call_response_body(0, body_size)
+            } else {
+                None
+            };
+            self.resume_authorized(body);
         } else {
-            let rejection_reason = match self
-                .get_http_call_response_headers()
+            // backend errors are handled above, so this is a 4xx
+            let rejection_reason = response_headers
                 .into_iter()
                 .find(|(key, _)| key.as_str() == "3scale-rejection-reason")
-            {
-                None => "None".to_string(),
-                Some((_, reason)) => reason,
-            };
+                .map(|(_, reason)| reason);
+            let reason = rejection_reason.as_deref().unwrap_or("None");
 
-            if rejection_reason == "limits_exceeded" {
-                info!(self, "on_http_call_response: limits_exceeded {}", token_id);
-                self.send_http_response(429, vec![], Some(b"Usage limit exceeded\n"));
-            } else {
-                info!(self, "on_http_call_response: not authorized {}", token_id);
-                self.send_http_response(403, vec![], Some(b"Authentication failed\n"));
+            // try the next application, if any, unless the current one was
+            // recognized but has run out of its limits
+            if reason != "limits_exceeded" && !self.pending_authreps.is_empty() {
+                info!(
+                    self,
+                    "on_http_call_response: not authorized {} ({}), trying next application",
+                    token_id,
+                    reason
+                );
+                let (app, request) = self.pending_authreps.remove(0);
+                if let Some(in_flight) = self.current_authrep.as_mut() {
+                    in_flight.app = app;
+                }
+                match self.dispatch_authrep(&request) {
+                    Ok(call) => self.backend_call = Some(call),
+                    Err(e) => {
+                        error!(self, "on_http_call_response: {}", e);
+                        if self.allow_on_backend_error(BackendError::Dispatch) {
+                            self.resume_authorized(None);
+                        } else {
+                            self.send_backend_unavailable(BackendError::Dispatch);
+                        }
+                    }
+                }
+                return;
+            }
+
+            info!(
+                self,
+                "on_http_call_response: not authorized {} ({})", token_id, reason
+            );
+            let service_id = self
+                .current_authrep
+                .as_ref()
+                .map(|in_flight| in_flight.service_id.clone())
+                .unwrap_or_default();
+            self.cache_authorization(false);
+            match rejection_reason {
+                Some(reason) => self.send_rejection(service_id.as_str(), reason.as_str()),
+                None => self.send_error_response(
+                    FailureClass::InvalidCredentials,
+                    Some(service_id.as_str()),
+                    403,
+                    vec![],
+                    "Authentication failed\n",
+                ),
             }
         }
     }
@@ -228,7 +1130,7 @@ impl HttpAuthThreescale {
                 ("x-3scale-app-id", app_id_key.as_str())
             }
             Application::UserKey(user_key) => ("x-3scale-user-key", user_key.as_ref()),
-            Application::OAuthToken(_token) => anyhow::bail!("Oauth token not supported"),
+            Application::OAuthToken(token) => ("x-3scale-access-token", token.as_ref()),
         };
 
         // Adding threescale info as request headers
@@ -248,3 +1150,36 @@ impl HttpAuthThreescale {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_buffers_request_bodies_up_to_the_limit() {
+        assert_eq!(BodyProgress::new(10, 4, false), BodyProgress::Partial);
+        assert_eq!(BodyProgress::new(10, 10, true), BodyProgress::Complete);
+        assert_eq!(BodyProgress::new(10, 11, false), BodyProgress::OverLimit);
+        assert_eq!(BodyProgress::new(10, 11, true), BodyProgress::OverLimit);
+    }
+
+    #[test]
+    fn it_tells_backend_errors_from_answers() {
+        assert_eq!(BackendError::check_status(Some(200)), Ok(200));
+        assert_eq!(BackendError::check_status(Some(409)), Ok(409));
+
+        let error = BackendError::check_status(Some(503)).unwrap_err();
+        assert_eq!(error, BackendError::ServerError(503));
+        assert_eq!(error.class(), FailureClass::BackendUnavailable);
+        assert_eq!(error.response().0, 502);
+
+        assert_eq!(
+            BackendError::check_status(Some(302)),
+            Err(BackendError::Malformed)
+        );
+        assert_eq!(
+            BackendError::check_status(None),
+            Err(BackendError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// Host properties (Envoy attributes) are returned as raw bytes: strings are
+// returned as is, and maps such as `request.headers` are serialized using the
+// same pairs format used by the proxy-wasm ABI for header maps.
+use std::convert::TryInto;
+
+// Deserializes a map in the pairs format: a little endian u32 with the number
+// of pairs, followed by the sizes of each key and value as little endian u32s,
+// and then the keys and values themselves, each one followed by a NUL byte.
+pub fn deserialize_pairs(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    let read_u32 = |offset: usize| -> Option<usize> {
+        bytes
+            .get(offset..offset + 4)
+            .and_then(|b| b.try_into().ok())
+            .map(|b| u32::from_le_bytes(b) as usize)
+    };
+
+    let count = read_u32(0)?;
+    let mut sizes_offset: usize = 4;
+    let mut data_offset = sizes_offset.checked_add(count.checked_mul(8)?)?;
+    // refuse bogus counts before allocating
+    if data_offset > bytes.len() {
+        return None;
+    }
+
+    let mut pairs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let key_size = read_u32(sizes_offset)?;
+        let value_size = read_u32(sizes_offset + 4)?;
+        sizes_offset += 8;
+
+        let key = bytes.get(data_offset..data_offset.checked_add(key_size)?)?;
+        data_offset += key_size + 1;
+        let value = bytes.get(data_offset..data_offset.checked_add(value_size)?)?;
+        data_offset += value_size + 1;
+
+        pairs.push((
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+    }
+
+    Some(pairs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn serialize_pairs(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut bytes = (pairs.len() as u32).to_le_bytes().to_vec();
+        for (k, v) in pairs {
+            bytes.extend_from_slice(&(k.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+        }
+        for (k, v) in pairs {
+            bytes.extend_from_slice(k.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(v.as_bytes());
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn it_deserializes_pairs() {
+        let pairs = [
+            (":path", "/"),
+            ("x-client-cert", "CN=client"),
+            ("empty", ""),
+        ];
+        let bytes = serialize_pairs(&pairs);
+        let res = deserialize_pairs(bytes.as_slice()).expect("failed to deserialize pairs");
+        assert_eq!(
+            res.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>(),
+            pairs.to_vec()
+        );
+
+        assert_eq!(
+            deserialize_pairs(serialize_pairs(&[]).as_slice()),
+            Some(vec![])
+        );
+        assert_eq!(deserialize_pairs(&bytes[..bytes.len() - 3]), None);
+        assert_eq!(deserialize_pairs(&[0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(deserialize_pairs(&[]), None);
+    }
+}
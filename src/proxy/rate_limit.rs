@@ -0,0 +1,93 @@
+// Rate limiting information from the limit_headers backend extension, exposed
+// to clients using the RateLimit header fields and Retry-After.
+//
+// Backend uses -1 for unknown or unlimited values, in which case no headers
+// are emitted.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    // seconds until the limit resets
+    reset: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn from_headers<'h>(headers: impl Iterator<Item = (&'h str, &'h str)>) -> Option<Self> {
+        let mut rl = Self {
+            limit: None,
+            remaining: None,
+            reset: None,
+        };
+
+        for (key, value) in headers {
+            let value = value.trim().parse::<u64>().ok();
+            match key.to_ascii_lowercase().as_str() {
+                "3scale-limit-max-value" => rl.limit = value,
+                "3scale-limit-remaining" => rl.remaining = value,
+                "3scale-limit-reset" => rl.reset = value,
+                _ => (),
+            }
+        }
+
+        if rl.limit.is_none() && rl.remaining.is_none() && rl.reset.is_none() {
+            None
+        } else {
+            Some(rl)
+        }
+    }
+
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        [
+            ("RateLimit-Limit", self.limit),
+            ("RateLimit-Remaining", self.remaining),
+            ("RateLimit-Reset", self.reset),
+        ]
+        .iter()
+        .filter_map(|&(name, value)| value.map(|v| (name, v.to_string())))
+        .collect()
+    }
+
+    // For responses rejecting requests for exceeding limits.
+    pub fn retry_after(&self) -> Option<(&'static str, String)> {
+        self.reset.map(|reset| ("Retry-After", reset.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_limit_headers() {
+        let headers = [
+            (":status", "409"),
+            ("3scale-limit-max-value", "100"),
+            ("3scale-limit-remaining", "0"),
+            ("3scale-limit-reset", "42"),
+        ];
+        let rl = RateLimit::from_headers(headers.iter().copied()).unwrap();
+        assert_eq!(
+            rl.headers(),
+            vec![
+                ("RateLimit-Limit", "100".to_string()),
+                ("RateLimit-Remaining", "0".to_string()),
+                ("RateLimit-Reset", "42".to_string()),
+            ]
+        );
+        assert_eq!(rl.retry_after(), Some(("Retry-After", "42".to_string())));
+
+        let headers = [
+            ("3scale-limit-max-value", "-1"),
+            ("3scale-limit-remaining", "-1"),
+            ("3scale-limit-reset", "-1"),
+        ];
+        let rl = RateLimit::from_headers(headers.iter().copied());
+        assert_eq!(rl, None);
+
+        assert_eq!(
+            RateLimit::from_headers([(":status", "200")].iter().copied()),
+            None
+        );
+    }
+}
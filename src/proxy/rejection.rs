@@ -0,0 +1,181 @@
+// Reasons backend gives for rejecting authorizations, each one with its own
+// response and a counter of the requests rejected for it.
+//
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{MetricType, Status};
+
+use crate::configuration::FailureClass;
+
+const METRIC_PREFIX: &str = "threescale_wasm_auth.rejections.";
+const BACKEND_UNAVAILABLE_METRIC: &str = "threescale_wasm_auth.backend_unavailable";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    UserKeyInvalid,
+    ApplicationNotFound,
+    ApplicationKeyInvalid,
+    ApplicationNotActive,
+    LimitsExceeded,
+    ReferrerNotAllowed,
+    OauthNotEnabled,
+    RedirectUriInvalid,
+    ServiceTokenInvalid,
+    ProviderKeyInvalid,
+    ServiceIdInvalid,
+    MetricInvalid,
+    UsageValueInvalid,
+    // reasons unknown to us, or none at all
+    Other,
+}
+
+impl From<&str> for Rejection {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "user_key_invalid" => Self::UserKeyInvalid,
+            "application_not_found" => Self::ApplicationNotFound,
+            "application_key_invalid" => Self::ApplicationKeyInvalid,
+            "application_not_active" => Self::ApplicationNotActive,
+            "limits_exceeded" => Self::LimitsExceeded,
+            "referrer_not_allowed" => Self::ReferrerNotAllowed,
+            "oauth_not_enabled" => Self::OauthNotEnabled,
+            "redirect_uri_invalid" | "redirect_url_invalid" => Self::RedirectUriInvalid,
+            "service_token_invalid" => Self::ServiceTokenInvalid,
+            "provider_key_invalid" => Self::ProviderKeyInvalid,
+            "service_id_invalid" => Self::ServiceIdInvalid,
+            "metric_invalid" => Self::MetricInvalid,
+            "usage_value_invalid" => Self::UsageValueInvalid,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UserKeyInvalid => "user_key_invalid",
+            Self::ApplicationNotFound => "application_not_found",
+            Self::ApplicationKeyInvalid => "application_key_invalid",
+            Self::ApplicationNotActive => "application_not_active",
+            Self::LimitsExceeded => "limits_exceeded",
+            Self::ReferrerNotAllowed => "referrer_not_allowed",
+            Self::OauthNotEnabled => "oauth_not_enabled",
+            Self::RedirectUriInvalid => "redirect_uri_invalid",
+            Self::ServiceTokenInvalid => "service_token_invalid",
+            Self::ProviderKeyInvalid => "provider_key_invalid",
+            Self::ServiceIdInvalid => "service_id_invalid",
+            Self::MetricInvalid => "metric_invalid",
+            Self::UsageValueInvalid => "usage_value_invalid",
+            Self::Other => "other",
+        }
+    }
+
+    // What went wrong, for the logs.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::UserKeyInvalid => "the user key is not known to the service",
+            Self::ApplicationNotFound => "the application id is not known to the service",
+            Self::ApplicationKeyInvalid => "the application key is missing or wrong",
+            Self::ApplicationNotActive => "the application is suspended or pending approval",
+            Self::LimitsExceeded => "the application exceeded its usage limits",
+            Self::ReferrerNotAllowed => "the referrer is not allowed for the application",
+            Self::OauthNotEnabled => "the service does not use OAuth authentication",
+            Self::RedirectUriInvalid => "the redirect URI does not match the application's",
+            Self::ServiceTokenInvalid => "the service token is not valid for the service",
+            Self::ProviderKeyInvalid => "the provider key is not valid",
+            Self::ServiceIdInvalid => "the service id is not known to backend",
+            Self::MetricInvalid => "a metric in the mapping rules does not exist",
+            Self::UsageValueInvalid => "a usage value in the mapping rules is not valid",
+            Self::Other => "backend did not give a known reason",
+        }
+    }
+
+    // Whether the client, rather than our configuration, is to blame.
+    pub fn is_client_error(&self) -> bool {
+        !matches!(
+            self,
+            Self::ServiceTokenInvalid
+                | Self::ProviderKeyInvalid
+                | Self::ServiceIdInvalid
+                | Self::MetricInvalid
+                | Self::UsageValueInvalid
+        )
+    }
+
+    pub fn class(&self) -> FailureClass {
+        match self {
+            Self::UserKeyInvalid => FailureClass::UserKeyInvalid,
+            Self::ApplicationNotFound => FailureClass::ApplicationNotFound,
+            Self::ApplicationKeyInvalid => FailureClass::ApplicationKeyInvalid,
+            Self::ApplicationNotActive => FailureClass::ApplicationNotActive,
+            Self::LimitsExceeded => FailureClass::LimitsExceeded,
+            Self::ReferrerNotAllowed => FailureClass::ReferrerNotAllowed,
+            Self::OauthNotEnabled => FailureClass::OauthNotEnabled,
+            Self::RedirectUriInvalid => FailureClass::RedirectUriInvalid,
+            Self::ServiceTokenInvalid
+            | Self::ProviderKeyInvalid
+            | Self::ServiceIdInvalid
+            | Self::MetricInvalid
+            | Self::UsageValueInvalid => FailureClass::ServiceMisconfigured,
+            Self::Other => FailureClass::InvalidCredentials,
+        }
+    }
+
+    // The default response, before applying the configured ones.
+    pub fn response(&self) -> (u32, &'static str) {
+        match self {
+            Self::UserKeyInvalid => (403, "User key invalid\n"),
+            Self::ApplicationNotFound => (403, "Application not found\n"),
+            Self::ApplicationKeyInvalid => (403, "Application key invalid\n"),
+            Self::ApplicationNotActive => (403, "Application not active\n"),
+            Self::LimitsExceeded => (429, "Usage limit exceeded\n"),
+            Self::ReferrerNotAllowed => (403, "Referrer not allowed\n"),
+            Self::OauthNotEnabled => (403, "OAuth not enabled\n"),
+            Self::RedirectUriInvalid => (400, "Redirect URI invalid\n"),
+            // not something clients can fix
+            Self::ServiceTokenInvalid
+            | Self::ProviderKeyInvalid
+            | Self::ServiceIdInvalid
+            | Self::MetricInvalid
+            | Self::UsageValueInvalid => (500, "Internal Server Error\n"),
+            Self::Other => (403, "Authentication failed\n"),
+        }
+    }
+
+    // Counts the rejection in its own metric.
+    pub fn record(&self) -> Result<(), Status> {
+        increment_counter(format!("{}{}", METRIC_PREFIX, self.as_str()).as_str())
+    }
+}
+
+// Counts requests denied because backend could not answer, which are not
+// rejections.
+pub fn record_backend_unavailable() -> Result<(), Status> {
+    increment_counter(BACKEND_UNAVAILABLE_METRIC)
+}
+
+fn increment_counter(name: &str) -> Result<(), Status> {
+    // defining an existing metric returns its id
+    let metric = hostcalls::define_metric(MetricType::Counter, name)?;
+    hostcalls::increment_metric(metric, 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_rejection_reasons() {
+        let reason = Rejection::from("application_key_invalid");
+        assert_eq!(reason.as_str(), "application_key_invalid");
+        assert_eq!(
+            reason.class().fallback(),
+            Some(FailureClass::InvalidCredentials)
+        );
+        assert_eq!(
+            Rejection::from("redirect_url_invalid"),
+            Rejection::RedirectUriInvalid
+        );
+        assert_eq!(Rejection::from("None"), Rejection::Other);
+        assert!(!Rejection::from("metric_invalid").is_client_error());
+    }
+}
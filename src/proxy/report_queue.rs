@@ -0,0 +1,229 @@
+// Cross-worker usage report pipeline.
+//
+// Envoy runs a VM per worker thread, so HTTP contexts in every worker enqueue
+// their usage records into a shared queue, and a single root context, holding
+// a lease in shared data, drains it and sends one report per service.
+//
+use std::collections::BTreeMap;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::{MetricType, Status};
+use serde::{Deserialize, Serialize};
+
+use super::cache::App;
+
+const QUEUE_NAME: &str = "3scale.reports";
+const CONSUMER_KEY: &str = "3scale.reports.consumer";
+const QUEUED_METRIC: &str = "threescale_wasm_auth.reports.queued";
+const DROPPED_METRIC: &str = "threescale_wasm_auth.reports.dropped";
+// Records dequeued per tick, leaving the rest for the next one.
+const MAX_DRAIN: usize = 65536;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("proxy failed to access the report queue: {0:?}")]
+    Proxy(Status),
+    #[error("failed to (de)serialize usage record: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        Self::Proxy(status)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub service_id: String,
+    pub app: App,
+    pub usages: BTreeMap<String, i64>,
+    // seconds since the epoch
+    pub timestamp: u64,
+}
+
+// Usages of an application aggregated from its records.
+#[derive(Debug, Clone)]
+pub struct PendingUsage {
+    pub app: App,
+    pub usages: BTreeMap<String, i64>,
+    pub oldest: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReportQueue {
+    queue_id: u32,
+    queued_metric: u32,
+    dropped_metric: u32,
+    max_size: u64,
+}
+
+// Adds the usages of the record to those pending for its service and application.
+fn merge(aggregated: &mut BTreeMap<String, BTreeMap<App, PendingUsage>>, record: Record) {
+    let Record {
+        service_id,
+        app,
+        usages,
+        timestamp,
+    } = record;
+
+    let pending = aggregated
+        .entry(service_id)
+        .or_default()
+        .entry(app.clone())
+        .or_insert_with(|| PendingUsage {
+            app,
+            usages: BTreeMap::new(),
+            oldest: timestamp,
+        });
+    pending.oldest = pending.oldest.min(timestamp);
+    for (metric, delta) in usages {
+        *pending.usages.entry(metric).or_insert(0) += delta;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    owner: u32,
+    expires_at: u64,
+}
+
+impl ReportQueue {
+    // Registers the queue and its metrics. All VMs share the same ones.
+    pub fn register<C: Context>(ctx: &C, max_size: u64) -> Result<Self, Error> {
+        Ok(Self {
+            queue_id: ctx.register_shared_queue(QUEUE_NAME),
+            queued_metric: hostcalls::define_metric(MetricType::Gauge, QUEUED_METRIC)?,
+            dropped_metric: hostcalls::define_metric(MetricType::Counter, DROPPED_METRIC)?,
+            max_size,
+        })
+    }
+
+    pub fn dropped(&self) -> u64 {
+        hostcalls::get_metric(self.dropped_metric).unwrap_or(0)
+    }
+
+    // Returns whether the record was enqueued rather than dropped.
+    pub fn enqueue<C: Context>(&self, ctx: &C, record: &Record) -> Result<bool, Error> {
+        // the bound is approximate, since other workers enqueue concurrently
+        let queued = hostcalls::get_metric(self.queued_metric)?;
+        if queued >= self.max_size {
+            hostcalls::increment_metric(self.dropped_metric, 1)?;
+            return Ok(false);
+        }
+
+        let data = serde_json::to_vec(record)?;
+        if let Err(status) = ctx.enqueue_shared_queue(self.queue_id, Some(data.as_slice())) {
+            hostcalls::increment_metric(self.dropped_metric, 1)?;
+            return Err(status.into());
+        }
+        hostcalls::increment_metric(self.queued_metric, 1)?;
+
+        Ok(true)
+    }
+
+    // Dequeues records and aggregates their usages per service and application.
+    pub fn drain<C: Context>(&self, ctx: &C) -> Result<BTreeMap<String, Vec<PendingUsage>>, Error> {
+        let mut aggregated = BTreeMap::<String, BTreeMap<App, PendingUsage>>::new();
+        let mut dequeued = 0;
+
+        while dequeued < MAX_DRAIN {
+            let data = match ctx.dequeue_shared_queue(self.queue_id)? {
+                Some(data) => data,
+                None => break,
+            };
+            dequeued += 1;
+
+            let record = match serde_json::from_slice::<Record>(data.as_slice()) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::error!("dropping malformed usage record: {}", e);
+                    hostcalls::increment_metric(self.dropped_metric, 1)?;
+                    continue;
+                }
+            };
+
+            merge(&mut aggregated, record);
+        }
+
+        if dequeued > 0 {
+            hostcalls::increment_metric(self.queued_metric, -(dequeued as i64))?;
+        }
+
+        Ok(aggregated
+            .into_iter()
+            .map(|(service_id, apps)| (service_id, apps.into_values().collect()))
+            .collect())
+    }
+}
+
+// Takes or renews the lease to be the single consumer of the queue.
+pub fn acquire_consumer<C: Context>(ctx: &C, owner: u32, now: u64, lease: u64) -> bool {
+    let (data, cas) = ctx.get_shared_data(CONSUMER_KEY);
+    let current = data
+        .filter(|data| !data.is_empty())
+        .and_then(|data| serde_json::from_slice::<Lease>(data.as_slice()).ok());
+
+    match current {
+        Some(current) if current.owner != owner && current.expires_at > now => false,
+        _ => {
+            let lease = Lease {
+                owner,
+                expires_at: now.saturating_add(lease),
+            };
+            serde_json::to_vec(&lease).ok().map_or(false, |data| {
+                // a CAS mismatch means another root context took it first
+                ctx.set_shared_data(CONSUMER_KEY, Some(data.as_slice()), cas)
+                    .is_ok()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(app: &str, usages: &[(&str, i64)], timestamp: u64) -> Record {
+        Record {
+            service_id: "42".into(),
+            app: App::UserKey(app.into()),
+            usages: usages.iter().map(|&(m, d)| (m.to_string(), d)).collect(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn it_round_trips_records() {
+        let record = Record {
+            app: App::AppId("app".into(), Some("key".into())),
+            ..record("", &[("hits", 2)], 1_700_000_000)
+        };
+        let data = serde_json::to_vec(&record).unwrap();
+        let decoded: Record = serde_json::from_slice(data.as_slice()).unwrap();
+
+        assert_eq!(decoded.service_id, record.service_id);
+        assert_eq!(decoded.app, record.app);
+        assert_eq!(decoded.usages, record.usages);
+        assert_eq!(decoded.timestamp, record.timestamp);
+    }
+
+    #[test]
+    fn it_merges_records_per_app() {
+        let mut aggregated = BTreeMap::new();
+        merge(&mut aggregated, record("a", &[("hits", 1)], 20));
+        merge(
+            &mut aggregated,
+            record("a", &[("hits", 2), ("bytes", 10)], 10),
+        );
+        merge(&mut aggregated, record("b", &[("hits", 1)], 30));
+
+        let apps = &aggregated["42"];
+        assert_eq!(apps.len(), 2);
+        let pending = &apps[&App::UserKey("a".into())];
+        assert_eq!(pending.usages["hits"], 3);
+        assert_eq!(pending.usages["bytes"], 10);
+        assert_eq!(pending.oldest, 10);
+    }
+}
@@ -18,12 +18,48 @@ mod helpers {
         (path, v.next())
     }
 
+    // Values can optionally be surrounded by double quotes (RFC 6265, section 4.1.1).
+    fn unquote_cookie_value(value: &str) -> &str {
+        value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value)
+    }
+
     fn extract_cookies(cookie_value: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
         cookie_value.split(';').map(|kv| {
             let mut kviter = kv.splitn(2, '=');
-            (kviter.next().unwrap(), kviter.next())
+            (
+                kviter.next().unwrap().trim(),
+                kviter.next().map(|v| unquote_cookie_value(v.trim())),
+            )
         })
     }
+    // Returns the cookie header value without the named cookies, if any was there.
+    pub fn strip_cookies(cookie_value: &str, names: &[String]) -> Option<String> {
+        let mut stripped = false;
+        let kept = cookie_value
+            .split(';')
+            .zip(extract_cookies(cookie_value))
+            .filter_map(|(kv, (cookie, _))| {
+                if names.iter().any(|name| name.as_str() == cookie) {
+                    stripped = true;
+                    None
+                } else if kv.trim().is_empty() {
+                    None
+                } else {
+                    Some(kv.trim())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if stripped {
+            Some(kept.join("; "))
+        } else {
+            None
+        }
+    }
+
     pub fn get_cookie<'a>(cookie_value: &'a str, name: &str) -> Option<Option<&'a str>> {
         extract_cookies(cookie_value)
             .find_map(|(cookie, v)| if cookie == name { Some(v) } else { None })
@@ -73,6 +109,12 @@ impl RequestHeaders {
             .find_map(|(h, v)| if h == name { Some(v.as_str()) } else { None })
     }
 
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter_map(move |(h, v)| if h == name { Some(v.as_str()) } else { None })
+    }
+
     pub fn iter(&self) -> std::slice::Iter<'_, (String, String)> {
         self.0.iter()
     }
@@ -88,6 +130,51 @@ impl RequestHeaders {
         }
     }
 
+    // Only requests announcing a body are held waiting for it: HTTP/2 and
+    // HTTP/3 ones without a body end their stream along their headers, so no
+    // body callback would ever resume them.
+    pub fn has_body(&self) -> bool {
+        match self.content_length() {
+            Some(len) => len > 0,
+            None => self.get("transfer-encoding").is_some(),
+        }
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.get("content-length")
+            .and_then(|len| len.trim().parse::<u64>().ok())
+    }
+
+    // Cookies can be split across multiple headers, ie. when using HTTP/2.
+    pub fn get_cookie(&self, name: &str) -> Option<&str> {
+        self.get_all("cookie")
+            .find_map(|cookie_value| helpers::get_cookie(cookie_value, name).flatten())
+    }
+
+    // The cookie header values left after removing the named cookies, or None
+    // if none of them is present. Headers left without cookies are dropped.
+    pub fn cookies_without(&self, names: &[String]) -> Option<Vec<String>> {
+        let mut stripped = false;
+        let cookies = self
+            .get_all("cookie")
+            .filter_map(
+                |cookie_value| match helpers::strip_cookies(cookie_value, names) {
+                    Some(kept) => {
+                        stripped = true;
+                        Some(kept).filter(|kept| !kept.is_empty())
+                    }
+                    None => Some(cookie_value.to_string()),
+                },
+            )
+            .collect();
+
+        if stripped {
+            Some(cookies)
+        } else {
+            None
+        }
+    }
+
     pub fn path_n_qs(&self) -> (&str, Option<&str>) {
         helpers::parse_path_n_qs(self.get(":path").unwrap())
     }
@@ -139,3 +226,60 @@ impl TryFrom<&RequestHeaders> for Url {
         rh.url()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::helpers::{get_cookie, strip_cookies};
+    use super::RequestHeaders;
+
+    fn request_headers(headers: &[(&str, &str)]) -> RequestHeaders {
+        RequestHeaders(
+            headers
+                .iter()
+                .map(|&(h, v)| (h.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn it_tells_whether_requests_have_a_body() {
+        // HTTP/2 and HTTP/3 requests need not announce their bodies, and those
+        // without one end their stream along their headers
+        let post = request_headers(&[(":method", "POST")]);
+        assert!(!post.has_body());
+
+        let empty = request_headers(&[(":method", "POST"), ("content-length", "0")]);
+        assert!(!empty.has_body());
+
+        let chunked = request_headers(&[(":method", "POST"), ("transfer-encoding", "chunked")]);
+        assert!(chunked.has_body());
+        let sized = request_headers(&[(":method", "PUT"), ("content-length", "12")]);
+        assert!(sized.has_body());
+    }
+
+    #[test]
+    fn it_parses_cookie_values() {
+        let cookie = r#"session=abc; user_key = "0123456789abcdef" ;flag; empty="#;
+
+        assert_eq!(get_cookie(cookie, "session"), Some(Some("abc")));
+        assert_eq!(
+            get_cookie(cookie, "user_key"),
+            Some(Some("0123456789abcdef"))
+        );
+        assert_eq!(get_cookie(cookie, "flag"), Some(None));
+        assert_eq!(get_cookie(cookie, "empty"), Some(Some("")));
+        assert_eq!(get_cookie(cookie, "missing"), None);
+    }
+
+    #[test]
+    fn it_strips_cookies() {
+        let names = vec!["user_key".to_string()];
+
+        assert_eq!(
+            strip_cookies("session=abc; user_key=0123;flag", &names).as_deref(),
+            Some("session=abc; flag")
+        );
+        assert_eq!(strip_cookies("user_key=0123", &names).as_deref(), Some(""));
+        assert_eq!(strip_cookies("session=abc", &names), None);
+    }
+}
@@ -7,13 +7,17 @@ use std::time::SystemTime;
 use crate::configuration::Configuration;
 use crate::log::IdentLogger;
 use crate::proxy::config_fetcher::{self, proxy, ConfigFetcher, Fetcher, FetcherState};
-use crate::threescale::{MappingRule, Usage};
+use crate::threescale::{Cache, MappingRule, OnBackendError, Usage};
 use crate::util::rand::thread_rng::{thread_rng_init_fallible, ThreadRng};
 use crate::util::serde::ErrorLocation;
 
 use threescalers::http::mapping_rule::{Method, RestRule};
 
-use super::http_context::HttpAuthThreescale;
+use super::authrep;
+use super::backend_health::{BackendHealth, SharedHealth};
+use super::cache;
+use super::http_context::{BodyBuffering, HttpAuthThreescale};
+use super::report_queue::{self, ReportQueue};
 
 const MIN_SYNC: u64 = 20;
 
@@ -25,10 +29,16 @@ pub(super) struct RootAuthThreescale {
     id: u32,
     log_id: String,
     config_deadline: SystemTime,
+    report_queue: Option<ReportQueue>,
+    // in-flight usage reports
+    report_tokens: Vec<u32>,
+    reports_dropped: u64,
+    // shared with the HTTP contexts
+    backend_health: SharedHealth,
 }
 
 impl RootAuthThreescale {
-    pub const fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             vm_configuration: None,
             configuration: None,
@@ -37,6 +47,10 @@ impl RootAuthThreescale {
             id: 0,
             log_id: String::new(),
             config_deadline: std::time::UNIX_EPOCH,
+            report_queue: None,
+            report_tokens: Vec::new(),
+            reports_dropped: 0,
+            backend_health: BackendHealth::new_shared(),
         }
     }
 }
@@ -47,6 +61,12 @@ impl IdentLogger for RootAuthThreescale {
     }
 }
 
+// Seeding only needs the time from the host, and unlike the root context,
+// which shares state with HTTP contexts, this can be sent across threads.
+struct SeedingContext;
+
+impl Context for SeedingContext {}
+
 impl Context for RootAuthThreescale {
     fn on_registered(&mut self, context_id: u32) {
         use crate::log::LogLevel;
@@ -56,7 +76,7 @@ impl Context for RootAuthThreescale {
         // Initialize the PRNG for this thread in the root context
         // This only needs to happen once per thread. Since we are
         // single-threaded, this means it just needs to happen once.
-        self.rng = match thread_rng_init_fallible(self, context_id) {
+        self.rng = match thread_rng_init_fallible(&SeedingContext, context_id) {
             Ok(r) => r,
             Err(e) => {
                 // No access yet to an initialized identity for logging, use raw API.
@@ -105,6 +125,22 @@ impl Context for RootAuthThreescale {
         _body_size: usize,
         _num_trailers: usize,
     ) {
+        if let Some(idx) = self.report_tokens.iter().position(|&t| t == token_id) {
+            self.report_tokens.swap_remove(idx);
+            let status = self
+                .get_http_call_response_headers()
+                .into_iter()
+                .find_map(|(key, value)| if key == ":status" { Some(value) } else { None });
+            match status.as_deref() {
+                Some("200") | Some("202") => debug!(self, "usage report {} accepted", token_id),
+                status => warn!(
+                    self,
+                    "usage report {} failed with status {:?}", token_id, status
+                ),
+            }
+            return;
+        }
+
         let idx = Fetcher::with(|vcf| {
             vcf.iter_mut()
                 .position(|cf| cf.token_id().map(|t| t == token_id).unwrap_or(false))
@@ -153,9 +189,10 @@ impl Context for RootAuthThreescale {
                             .unwrap(),
                             usages: vec![Usage {
                                 name: metric_name.unwrap_or_else(|| "Hits".into()),
-                                delta: proxy_rule.delta as i64,
+                                delta: (proxy_rule.delta as i64).into(),
                             }],
                             last: proxy_rule.last,
+                            mode: None,
                         })
                     }
                     rules_updated = true;
@@ -258,6 +295,11 @@ impl RootContext for RootAuthThreescale {
             }
         };
 
+        if let Err(e) = conf.get().check() {
+            error!(self, "fatal configuration error: {}", e);
+            return false;
+        }
+
         self.configuration = conf.into();
         info!(
             self,
@@ -267,6 +309,28 @@ impl RootContext for RootAuthThreescale {
         // cancel any previous work updating configurations
         Fetcher::clear();
 
+        let upstreams = self
+            .get_configuration()
+            .and_then(|conf| conf.backend())
+            .map_or(0, |backend| backend.upstreams().len());
+        self.backend_health.borrow_mut().reset(upstreams);
+
+        self.report_queue = None;
+        let settings = self
+            .report_queue_settings()
+            .map(|cache| (cache.queue_size(), cache.report_interval()));
+        if let Some((queue_size, report_interval)) = settings {
+            match ReportQueue::register(self, queue_size) {
+                Ok(queue) => self.report_queue = Some(queue),
+                Err(e) => {
+                    error!(self, "failed to set up the usage report queue: {}", e);
+                    return false;
+                }
+            }
+            // set_next_tick() will shorten the period if system is configured
+            self.set_tick_period(report_interval);
+        }
+
         self.on_tick();
 
         true
@@ -279,6 +343,15 @@ impl RootContext for RootAuthThreescale {
             configuration: self.configuration.as_ref().unwrap().clone(),
             id: self.rng.next_u32(),
             log_id: format!("{} ({}/http)", self.id, self.context_id),
+            body_buffering: BodyBuffering::Disabled,
+            request_body: None,
+            pending_authreps: Vec::new(),
+            current_authrep: None,
+            deferred_report: None,
+            rate_limit: None,
+            report_queue: self.report_queue,
+            backend_call: None,
+            backend_health: self.backend_health.clone(),
         };
 
         Some(ChildContext::HttpContext(Box::new(ctx)))
@@ -286,14 +359,19 @@ impl RootContext for RootAuthThreescale {
 
     fn on_tick(&mut self) {
         debug!(self, "executing on_tick");
+        self.flush_usage_reports();
+
         if let Some(config) = self.get_configuration() {
             if let Some(sys) = self.get_system_config() {
                 let current_time = self.get_current_time();
                 if current_time < self.config_deadline {
-                    warn!(
-                        self,
-                        "on_tick running while the configuration is still valid"
-                    );
+                    // expected when ticking to report usages
+                    if self.get_report_interval().is_none() {
+                        warn!(
+                            self,
+                            "on_tick running while the configuration is still valid"
+                        );
+                    }
                     return;
                 }
 
@@ -335,6 +413,167 @@ impl RootAuthThreescale {
         self.get_configuration().and_then(|conf| conf.system())
     }
 
+    // The usage report queue takes the usages of requests authorized from the
+    // cache, and of those let through while backend is unavailable, which
+    // use the default settings when there is no cache.
+    fn report_queue_settings(&self) -> Option<Cache> {
+        let conf = self.get_configuration()?;
+        let backend = conf.backend()?;
+        if let Some(cache) = backend.cache() {
+            return Some(cache.clone());
+        }
+
+        let fails_open = conf.services().map_or(false, |services| {
+            services
+                .iter()
+                .any(|svc| svc.on_backend_error() == OnBackendError::Allow)
+        });
+        if fails_open {
+            Some(Cache::default())
+        } else {
+            None
+        }
+    }
+
+    fn get_report_interval(&self) -> Option<Duration> {
+        self.report_queue_settings()
+            .map(|cache| cache.report_interval())
+    }
+
+    // Sends the usages accumulated by requests authorized from the cache in
+    // all workers, with a single report per service, if we are the consumer.
+    fn flush_usage_reports(&mut self) {
+        let queue = match self.report_queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        let interval = match self.get_report_interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let now = cache::now(self);
+        // let the lease outlive a couple of missed ticks
+        if !report_queue::acquire_consumer(self, self.id, now, 3 * interval.as_secs()) {
+            debug!(self, "not the usage report consumer");
+            return;
+        }
+
+        let dropped = queue.dropped();
+        if dropped > self.reports_dropped {
+            warn!(
+                self,
+                "{} usage records dropped since last report ({} in total)",
+                dropped - self.reports_dropped,
+                dropped
+            );
+            self.reports_dropped = dropped;
+        }
+
+        let per_service = match queue.drain(self) {
+            Ok(per_service) => per_service,
+            Err(e) => {
+                error!(self, "failed to drain usage report queue: {}", e);
+                return;
+            }
+        };
+
+        let config = match self.get_configuration() {
+            Some(config) => config,
+            None => return,
+        };
+        let backend = match config.backend() {
+            Some(backend) => backend,
+            None => return,
+        };
+        // reports go to the first upstream accepting them, with no retries
+        // once dispatched
+        let upstreams = backend.upstreams();
+        let candidates = self
+            .backend_health
+            .borrow_mut()
+            .candidates(backend, self.get_current_time(), || self.rng.next_u32())
+            .into_iter()
+            .filter_map(|idx| upstreams.get(idx).map(|&(upstream, _)| upstream))
+            .collect::<Vec<_>>();
+        let mut tokens = vec![];
+        for (service_id, pending) in per_service {
+            let service = match config
+                .services()
+                .and_then(|services| services.iter().find(|svc| svc.id() == service_id))
+            {
+                Some(service) => service,
+                None => {
+                    warn!(self, "dropping usages of unknown service {}", service_id);
+                    continue;
+                }
+            };
+
+            let request = match authrep::build_report(service, pending.as_slice()) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!(
+                        self,
+                        "failed to build usage report for service {}: {}", service_id, e
+                    );
+                    continue;
+                }
+            };
+
+            let (uri, body) = request.uri_and_body();
+            let headers = request
+                .headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>();
+
+            let result = candidates.iter().find_map(|upstream| {
+                upstream
+                    .call(
+                        self,
+                        uri.as_ref(),
+                        request.method.as_str(),
+                        headers.clone(),
+                        None,
+                        body.map(str::as_bytes),
+                        None,
+                        None,
+                    )
+                    .map_err(|e| {
+                        warn!(
+                            self,
+                            "failed to report usages of service {} to {}: {}",
+                            service_id,
+                            upstream.name(),
+                            e
+                        )
+                    })
+                    .ok()
+            });
+
+            match result {
+                Some(token) => {
+                    let oldest = pending.iter().map(|p| p.oldest).min().unwrap_or(now);
+                    debug!(
+                        self,
+                        "reporting {} applications of service {} with token {}, oldest usage {}s ago",
+                        pending.len(),
+                        service_id,
+                        token,
+                        now.saturating_sub(oldest)
+                    );
+                    tokens.push(token);
+                }
+                None => error!(
+                    self,
+                    "failed to report usages of service {} to any backend upstream", service_id
+                ),
+            }
+        }
+
+        self.report_tokens.extend(tokens);
+    }
+
     fn get_next_tick(&self) -> Option<(Duration, Duration)> {
         self.get_system_config().map(|sys| {
             let jitter = self.rng.next_u32() as u64 & 0x0F; // add 0-15 seconds on top
@@ -361,6 +600,10 @@ impl RootAuthThreescale {
                 .get_current_time()
                 .checked_add(original_ttl)
                 .unwrap_or(std::time::UNIX_EPOCH);
+            // keep reporting usages often enough
+            let tick = self
+                .get_report_interval()
+                .map_or(tick, |interval| tick.min(interval));
             self.set_tick_period(tick);
             tick
         })
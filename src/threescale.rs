@@ -1,13 +1,20 @@
 mod backend;
 mod credentials;
+mod credentials_policy;
 mod mapping_rule;
+mod oidc;
+mod report_condition;
 mod service;
+mod service_matcher;
 mod system;
 mod usage;
 
-pub use backend::Backend;
+pub use backend::{Backend, Cache, Extension, FailoverStrategy};
 pub use credentials::{Credentials, Error as CredentialsError};
+pub use credentials_policy::{CredentialKind, CredentialsPolicy};
 pub use mapping_rule::MappingRule;
-pub use service::{Environment, Service};
+pub use oidc::Oidc;
+pub use report_condition::ReportCondition;
+pub use service::{Environment, Mode, OnBackendError, Service, UpstreamData};
 pub use system::System;
-pub use usage::Usage;
+pub use usage::{parse_delta, Computed, Delta, ResponseHeader, Usage};
@@ -1,3 +1,6 @@
+use core::convert::TryFrom;
+use core::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
 use crate::upstream::Upstream;
@@ -8,7 +11,161 @@ pub struct Backend {
     pub name: Option<String>,
     pub upstream: Upstream,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub extensions: Option<Vec<String>>,
+    pub extensions: Option<Vec<Extension>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<Cache>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failover: Option<Failover>,
+}
+
+// Extensions of the Service Management API known to be supported.
+const KNOWN_EXTENSIONS: &[&str] = &[
+    "no_body",
+    "rejection_reason_header",
+    "limit_headers",
+    "hierarchy",
+    "flat_usage",
+    "list_app_keys",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExtensionError {
+    #[error("empty extension name")]
+    Empty,
+    #[error("unknown extension `{0}`, expected one of {1:?}")]
+    Unknown(String, &'static [&'static str]),
+}
+
+// An extension to send to backend, specified as `name` or `name=value`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Extension {
+    name: String,
+    value: Option<String>,
+}
+
+impl TryFrom<String> for Extension {
+    type Error = ExtensionError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (name, value) = match value.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+            None => (value.trim(), None),
+        };
+
+        if name.is_empty() {
+            return Err(ExtensionError::Empty);
+        }
+        if !KNOWN_EXTENSIONS.contains(&name) {
+            return Err(ExtensionError::Unknown(name.to_string(), KNOWN_EXTENSIONS));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+impl From<Extension> for String {
+    fn from(ext: Extension) -> Self {
+        match ext.value {
+            Some(value) => format!("{}={}", ext.name, value),
+            None => ext.name,
+        }
+    }
+}
+
+impl Extension {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    // Extensions without a value are enabled with "1".
+    pub fn value(&self) -> &str {
+        self.value.as_deref().unwrap_or("1")
+    }
+}
+
+// Authorizing from a local cache and reporting in batches.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cache {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_size: Option<u64>,
+}
+
+impl Cache {
+    pub fn ttl(&self) -> Duration {
+        let ttl = self.ttl.unwrap_or(60);
+        Duration::from_secs(ttl)
+    }
+
+    pub fn report_interval(&self) -> Duration {
+        let interval = self.report_interval.unwrap_or(10).max(1);
+        Duration::from_secs(interval)
+    }
+
+    // Maximum number of usage records waiting to be reported.
+    pub fn queue_size(&self) -> u64 {
+        self.queue_size.unwrap_or(10000)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverStrategy {
+    // in the order configured, starting with the backend's upstream
+    Ordered,
+    // randomly as per their weights
+    Weighted,
+}
+
+impl Default for FailoverStrategy {
+    fn default() -> Self {
+        Self::Ordered
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailoverUpstream {
+    pub upstream: Upstream,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+// Additional upstreams to dispatch calls to when others fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failover {
+    #[serde(default)]
+    pub strategy: FailoverStrategy,
+    // weight of the backend's upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    pub upstreams: Vec<FailoverUpstream>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_failures: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<u64>,
+}
+
+impl Failover {
+    pub fn strategy(&self) -> FailoverStrategy {
+        self.strategy
+    }
+
+    // Consecutive failures after which an upstream is considered unhealthy.
+    pub fn max_failures(&self) -> u32 {
+        self.max_failures.unwrap_or(3).max(1)
+    }
+
+    // Time an unhealthy upstream is avoided for.
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown.unwrap_or(30))
+    }
 }
 
 impl Backend {
@@ -21,8 +178,59 @@ impl Backend {
         &self.upstream
     }
 
-    #[allow(dead_code)]
-    pub const fn extensions(&self) -> Option<&Vec<String>> {
+    pub const fn extensions(&self) -> Option<&Vec<Extension>> {
         self.extensions.as_ref()
     }
+
+    pub const fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub const fn failover(&self) -> Option<&Failover> {
+        self.failover.as_ref()
+    }
+
+    // All upstreams along with their weights, starting with the main one.
+    pub fn upstreams(&self) -> Vec<(&Upstream, u32)> {
+        let failover = match self.failover.as_ref() {
+            Some(failover) => failover,
+            None => return vec![(&self.upstream, 1)],
+        };
+
+        std::iter::once((&self.upstream, failover.weight.unwrap_or(1)))
+            .chain(
+                failover
+                    .upstreams
+                    .iter()
+                    .map(|fu| (&fu.upstream, fu.weight.unwrap_or(1))),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_validates_extensions() {
+        let ext = Extension::try_from("flat_usage".to_string()).unwrap();
+        assert_eq!(ext.name(), "flat_usage");
+        assert_eq!(ext.value(), "1");
+
+        let ext = Extension::try_from(" hierarchy = 0 ".to_string()).unwrap();
+        assert_eq!(ext.name(), "hierarchy");
+        assert_eq!(ext.value(), "0");
+        assert_eq!(String::from(ext), "hierarchy=0");
+
+        assert!(matches!(
+            Extension::try_from("no_such_thing=1".to_string()),
+            Err(ExtensionError::Unknown(name, _)) if name == "no_such_thing"
+        ));
+        assert!(matches!(
+            Extension::try_from("=1".to_string()),
+            Err(ExtensionError::Empty)
+        ));
+        assert!(serde_json::from_str::<Vec<Extension>>(r#"["limit_headers", "bogus"]"#).is_err());
+    }
 }
@@ -1,23 +1,36 @@
+use proxy_wasm::traits::HttpContext;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use threescalers::application::Application;
 
+use super::{CredentialKind, CredentialsPolicy, Oidc};
 use crate::{
     configuration::Source,
     proxy::{request_headers::RequestHeaders, HttpAuthThreescale},
+    util::jwt,
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("credentials not found")]
     NotFound,
+    #[error("OIDC token rejected: {0}")]
+    Oidc(#[from] jwt::Error),
+    #[error("credentials not found, basic authentication required in realm {0}")]
+    BasicAuthRequired(String),
+    #[error("more than one kind of credentials found")]
+    Ambiguous,
+    #[error("conflicting values found for {0}")]
+    Conflicting(&'static str),
 }
 
-// Consider defining an order of look-up and short-circuiting?
+// The order of look-up and short-circuiting is defined by CredentialsPolicy.
 #[derive(Debug, Clone)]
 pub struct Credentials {
     user_key: Option<Vec<Source>>,
     app_id: Option<Vec<Source>>,
     app_key: Option<Vec<Source>>,
+    access_token: Option<Vec<Source>>,
+    oidc: Option<Oidc>,
 }
 
 impl Credentials {
@@ -26,11 +39,15 @@ impl Credentials {
         user_key: Option<Vec<Source>>,
         app_id: Option<Vec<Source>>,
         app_key: Option<Vec<Source>>,
+        access_token: Option<Vec<Source>>,
+        oidc: Option<Oidc>,
     ) -> Self {
         Self {
             user_key,
             app_id,
             app_key,
+            access_token,
+            oidc,
         }
     }
 
@@ -46,65 +63,200 @@ impl Credentials {
         self.app_key.as_ref()
     }
 
+    pub fn access_token(&self) -> Option<&Vec<Source>> {
+        self.access_token.as_ref()
+    }
+
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_ref()
+    }
+
+    // The amount of request body needed to resolve these credentials, if any.
+    pub fn body_limit(&self) -> Option<usize> {
+        [
+            self.user_key(),
+            self.app_id(),
+            self.app_key(),
+            self.access_token(),
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|sources| sources.iter())
+        .chain(
+            self.oidc()
+                .into_iter()
+                .flat_map(|oidc| oidc.sources().iter()),
+        )
+        .filter_map(Source::body_limit)
+        .max()
+    }
+
+    // The realm of the first basic_auth source, if any.
+    pub fn basic_auth_realm(&self) -> Option<&str> {
+        [
+            self.user_key(),
+            self.app_id(),
+            self.app_key(),
+            self.access_token(),
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|sources| sources.iter())
+        .find_map(Source::basic_auth_realm)
+    }
+
+    // Removes the credentials looked up by sources asked to strip them.
+    pub fn strip(&self, ctx: &dyn HttpContext) {
+        [
+            self.user_key(),
+            self.app_id(),
+            self.app_key(),
+            self.access_token(),
+        ]
+        .iter()
+        .flatten()
+        .flat_map(|sources| sources.iter())
+        .chain(
+            self.oidc()
+                .into_iter()
+                .flat_map(|oidc| oidc.sources().iter()),
+        )
+        .for_each(|source| source.strip(ctx));
+    }
+
     pub fn resolve(
         &self,
         ctx: &HttpAuthThreescale,
         rh: &RequestHeaders,
         url: &url::Url,
+        policy: &CredentialsPolicy,
     ) -> Result<Vec<Application>, Error> {
         let mut apps = vec![];
 
-        let user_key = self
-            .user_key()
-            .and_then(|sources| {
-                sources
-                    .iter()
-                    .find_map(|source| source.resolve(ctx, rh, url))
-                    .and_then(|values| values.get(0).map(|val| val.as_ref().to_string()))
-            })
-            .map(|user_key| Application::UserKey(user_key.into()));
-
-        let app_values = self.app_id().and_then(|sources| {
-            sources
-                .iter()
-                .find_map(|source| source.resolve(ctx, rh, url))
-                .map(|values| {
-                    let app_id = values.get(0).map(|val| val.as_ref().to_string());
-                    let app_key = values.get(1).map(|val| val.as_ref().to_string());
-                    (app_id, app_key)
-                })
-        });
-
-        let (app_id, app_key) = match app_values {
-            Some((id, key)) => (id, key),
-            _ => (None, None),
-        };
-
-        let app_key = app_key.or_else(|| {
-            self.app_key().and_then(|sources| {
-                sources
-                    .iter()
-                    .find_map(|source| source.resolve(ctx, rh, url))
-                    .and_then(|values| values.get(0).map(|k| k.as_ref().to_string()))
-            })
-        });
-
-        let app_id = app_id.map(|id| Application::AppId(id.into(), app_key.map(|k| k.into())));
-
-        if let Some(uk) = user_key {
-            apps.push(uk);
+        for kind in policy.kinds() {
+            if let Some(app) = self.resolve_kind(kind, ctx, rh, url, policy)? {
+                apps.push(app);
+                if policy.short_circuit() {
+                    break;
+                }
+            }
         }
 
-        if let Some(app) = app_id {
-            apps.push(app);
+        if apps.is_empty() {
+            return Err(self.basic_auth_realm().map_or(Error::NotFound, |realm| {
+                Error::BasicAuthRequired(realm.to_string())
+            }));
         }
 
-        if apps.is_empty() {
-            return Err(Error::NotFound);
+        if policy.reject_ambiguous() && apps.len() > 1 {
+            return Err(Error::Ambiguous);
         }
 
         Ok(apps)
     }
+
+    fn resolve_kind(
+        &self,
+        kind: CredentialKind,
+        ctx: &HttpAuthThreescale,
+        rh: &RequestHeaders,
+        url: &url::Url,
+        policy: &CredentialsPolicy,
+    ) -> Result<Option<Application>, Error> {
+        let check = policy.reject_conflicting();
+
+        let app = match kind {
+            CredentialKind::UserKey => {
+                resolve_sources(kind.as_str(), self.user_key(), 1, ctx, rh, url, check)?
+                    .and_then(|values| values.into_iter().next())
+                    .map(|user_key| Application::UserKey(user_key.into()))
+            }
+            CredentialKind::AppId => {
+                let (app_id, app_key) =
+                    match resolve_sources(kind.as_str(), self.app_id(), 2, ctx, rh, url, check)? {
+                        Some(values) => {
+                            let mut values = values.into_iter();
+                            (values.next(), values.next())
+                        }
+                        None => (None, None),
+                    };
+
+                // an app_key is only looked up if there is an app_id to go with it
+                let app_key = match (&app_id, app_key) {
+                    (None, _) => None,
+                    (Some(_), Some(app_key)) => Some(app_key),
+                    (Some(_), None) => {
+                        resolve_sources("app_key", self.app_key(), 1, ctx, rh, url, check)?
+                            .and_then(|values| values.into_iter().next())
+                    }
+                };
+
+                app_id.map(|id| Application::AppId(id.into(), app_key.map(|k| k.into())))
+            }
+            CredentialKind::AccessToken => {
+                resolve_sources(kind.as_str(), self.access_token(), 1, ctx, rh, url, check)?
+                    .and_then(|values| values.into_iter().next())
+                    .map(|token| Application::OAuthToken(token.into()))
+            }
+            // A token that is present but fails verification is an error rather
+            // than a miss, so that we never fall back to other credentials.
+            CredentialKind::Oidc => match self.oidc() {
+                Some(oidc) => oidc.resolve(ctx, rh, url)?,
+                None => None,
+            },
+        };
+
+        Ok(app)
+    }
+}
+
+// Resolves the first `take` values of the first source that resolves, or, when
+// checking for conflicts, of all sources, failing if they differ. Conflicts
+// are reported for the given field.
+fn resolve_sources(
+    field: &'static str,
+    sources: Option<&Vec<Source>>,
+    take: usize,
+    ctx: &HttpAuthThreescale,
+    rh: &RequestHeaders,
+    url: &url::Url,
+    check_conflicts: bool,
+) -> Result<Option<Vec<String>>, Error> {
+    let sources = match sources {
+        Some(sources) => sources,
+        None => return Ok(None),
+    };
+
+    let resolved = sources.iter().filter_map(|source| {
+        source.resolve(ctx, rh, url).map(|values| {
+            values
+                .into_iter()
+                .take(take)
+                .map(|val| val.into_owned())
+                .collect::<Vec<_>>()
+        })
+    });
+
+    select_values(field, resolved, check_conflicts)
+}
+
+// Picks the values of the first source, checking them against those of the
+// rest of sources if asked to.
+fn select_values(
+    field: &'static str,
+    mut resolved: impl Iterator<Item = Vec<String>>,
+    check_conflicts: bool,
+) -> Result<Option<Vec<String>>, Error> {
+    let first = match resolved.next() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+
+    if check_conflicts && resolved.any(|values| values != first) {
+        return Err(Error::Conflicting(field));
+    }
+
+    Ok(Some(first))
 }
 
 impl<'de> Deserialize<'de> for Credentials {
@@ -115,6 +267,8 @@ impl<'de> Deserialize<'de> for Credentials {
             UserKey,
             AppId,
             AppKey,
+            AccessToken,
+            Oidc,
         }
 
         struct CredentialsVisitor;
@@ -124,7 +278,7 @@ impl<'de> Deserialize<'de> for Credentials {
 
             fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
                 formatter.write_str(
-                    "credentials definition looking up user_key or app_id with optional app_key",
+                    "credentials definition looking up user_key, app_id with optional app_key, access_token, or oidc",
                 )
             }
 
@@ -132,6 +286,8 @@ impl<'de> Deserialize<'de> for Credentials {
                 let mut user_key = None;
                 let mut app_id = None;
                 let mut app_key = None;
+                let mut access_token = None;
+                let mut oidc = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -153,11 +309,23 @@ impl<'de> Deserialize<'de> for Credentials {
                             }
                             app_key = Some(map.next_value()?);
                         }
+                        Field::AccessToken => {
+                            if access_token.is_some() {
+                                return Err(de::Error::duplicate_field("access_token"));
+                            }
+                            access_token = Some(map.next_value()?);
+                        }
+                        Field::Oidc => {
+                            if oidc.is_some() {
+                                return Err(de::Error::duplicate_field("oidc"));
+                            }
+                            oidc = Some(map.next_value()?);
+                        }
                     }
                 }
-                if let (None, None) = (&user_key, &app_id) {
+                if let (None, None, None, None) = (&user_key, &app_id, &access_token, &oidc) {
                     return Err(de::Error::custom(
-                        "you must provide at least one of user_key or app_id",
+                        "you must provide at least one of user_key, app_id, access_token or oidc",
                     ));
                 }
 
@@ -165,6 +333,8 @@ impl<'de> Deserialize<'de> for Credentials {
                     user_key,
                     app_id,
                     app_key,
+                    access_token,
+                    oidc,
                 };
 
                 Ok(credentials)
@@ -173,7 +343,7 @@ impl<'de> Deserialize<'de> for Credentials {
 
         deserializer.deserialize_struct(
             "Credentials",
-            &["user_key", "app_id", "app_key"],
+            &["user_key", "app_id", "app_key", "access_token", "oidc"],
             CredentialsVisitor,
         )
     }
@@ -185,6 +355,7 @@ impl Serialize for Credentials {
 
         enum FieldAction<'n, 's> {
             Serialize(&'n str, &'s Vec<Source>),
+            SerializeOidc(&'n str, &'s Oidc),
             Skip(&'n str),
         }
 
@@ -204,22 +375,77 @@ impl Serialize for Credentials {
                 FieldAction::Serialize("app_key", app_key)
             });
 
-        let actions = [user_key_action, app_id_action, app_key_action];
+        let access_token_action = self
+            .access_token()
+            .map_or(FieldAction::Skip("access_token"), |access_token| {
+                FieldAction::Serialize("access_token", access_token)
+            });
+
+        let oidc_action = self.oidc().map_or(FieldAction::Skip("oidc"), |oidc| {
+            FieldAction::SerializeOidc("oidc", oidc)
+        });
+
+        let actions = [
+            user_key_action,
+            app_id_action,
+            app_key_action,
+            access_token_action,
+            oidc_action,
+        ];
 
         let mut state = serializer.serialize_struct(
             "Credentials",
             actions
                 .iter()
-                .filter(|&a| matches!(a, FieldAction::Serialize(_, _)))
+                .filter(|&a| !matches!(a, FieldAction::Skip(_)))
                 .count(),
         )?;
 
         for f in &actions {
             match f {
                 FieldAction::Serialize(key, value) => state.serialize_field(key, value)?,
+                FieldAction::SerializeOidc(key, value) => state.serialize_field(key, value)?,
                 FieldAction::Skip(key) => state.skip_field(key)?,
             }
         }
         state.end()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(values: &[&[&str]]) -> impl Iterator<Item = Vec<String>> {
+        values
+            .iter()
+            .map(|v| v.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn it_selects_the_first_source_values() {
+        let resolved = values(&[&["app", "key"], &["other"]]);
+        assert_eq!(
+            select_values("app_id", resolved, false).unwrap(),
+            Some(vec!["app".to_string(), "key".to_string()])
+        );
+        assert_eq!(select_values("app_id", values(&[]), true).unwrap(), None);
+    }
+
+    #[test]
+    fn it_rejects_conflicting_sources() {
+        let resolved = values(&[&["abc"], &["abc"], &["def"]]);
+        assert!(matches!(
+            select_values("app_key", resolved, true),
+            Err(Error::Conflicting("app_key"))
+        ));
+
+        let resolved = values(&[&["abc"], &["abc"]]);
+        assert_eq!(
+            select_values("user_key", resolved, true).unwrap(),
+            Some(vec!["abc".to_string()])
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    UserKey,
+    // app_id along with its optional app_key
+    AppId,
+    AccessToken,
+    Oidc,
+}
+
+impl CredentialKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UserKey => "user_key",
+            Self::AppId => "app_id",
+            Self::AccessToken => "access_token",
+            Self::Oidc => "oidc",
+        }
+    }
+}
+
+impl core::fmt::Display for CredentialKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialsPolicy {
+    // kinds left out are not looked up
+    #[serde(default = "defaults::order")]
+    pub order: Vec<CredentialKind>,
+    // stop looking up kinds once one resolves
+    #[serde(default)]
+    pub short_circuit: bool,
+    // reject requests presenting more than one kind of credentials
+    #[serde(default)]
+    pub reject_ambiguous: bool,
+    // reject requests whose sources resolve different values for a kind
+    #[serde(default)]
+    pub reject_conflicting: bool,
+    // send an authrep per application found until one authorizes
+    #[serde(default)]
+    pub try_all: bool,
+}
+
+mod defaults {
+    use super::CredentialKind;
+
+    pub(super) fn order() -> Vec<CredentialKind> {
+        vec![
+            CredentialKind::UserKey,
+            CredentialKind::AppId,
+            CredentialKind::AccessToken,
+            CredentialKind::Oidc,
+        ]
+    }
+}
+
+impl Default for CredentialsPolicy {
+    fn default() -> Self {
+        Self {
+            order: defaults::order(),
+            short_circuit: false,
+            reject_ambiguous: false,
+            reject_conflicting: false,
+            try_all: false,
+        }
+    }
+}
+
+impl CredentialsPolicy {
+    // The kinds to look up in order, each one once even if repeated.
+    pub fn kinds(&self) -> impl Iterator<Item = CredentialKind> + '_ {
+        self.order
+            .iter()
+            .enumerate()
+            .filter(move |&(idx, kind)| !self.order[..idx].contains(kind))
+            .map(|(_, &kind)| kind)
+    }
+
+    // Detecting ambiguity requires looking up all kinds.
+    pub fn short_circuit(&self) -> bool {
+        self.short_circuit && !self.reject_ambiguous
+    }
+
+    pub fn reject_ambiguous(&self) -> bool {
+        self.reject_ambiguous
+    }
+
+    pub fn reject_conflicting(&self) -> bool {
+        self.reject_conflicting
+    }
+
+    pub fn try_all(&self) -> bool {
+        self.try_all
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_deserializes_policy_defaults() {
+        let policy: CredentialsPolicy = serde_json::from_str("{}").unwrap();
+        assert_eq!(policy.order, defaults::order());
+        assert!(!policy.short_circuit());
+        assert!(!policy.reject_ambiguous());
+        assert!(!policy.reject_conflicting());
+        assert!(!policy.try_all());
+
+        let policy: CredentialsPolicy =
+            serde_json::from_str(r#"{"short_circuit": true, "reject_ambiguous": true}"#).unwrap();
+        // ambiguity can only be told by looking up all kinds
+        assert!(!policy.short_circuit());
+    }
+
+    #[test]
+    fn it_rejects_unknown_kinds() {
+        assert!(serde_json::from_str::<CredentialsPolicy>(r#"{"order": ["api_key"]}"#).is_err());
+    }
+
+    #[test]
+    fn it_visits_repeated_kinds_once() {
+        let policy: CredentialsPolicy =
+            serde_json::from_str(r#"{"order": ["app_id", "user_key", "app_id", "user_key"]}"#)
+                .unwrap();
+        assert_eq!(
+            policy.kinds().collect::<Vec<_>>(),
+            vec![CredentialKind::AppId, CredentialKind::UserKey]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use threescalers::http::mapping_rule::{Method, RestRule};
 
-use super::Usage;
+use super::{Mode, Usage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MappingRule {
@@ -10,6 +10,9 @@ pub struct MappingRule {
     pub usages: Vec<Usage>,
     #[serde(default)]
     pub last: bool,
+    // overrides the service's mode for requests matching this rule
+    #[serde(default)]
+    pub mode: Option<Mode>,
 }
 
 impl MappingRule {
@@ -44,4 +47,39 @@ impl MappingRule {
     pub fn is_last(&self) -> bool {
         self.last
     }
+
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_deserializes_rule_modes() {
+        let rule: MappingRule = serde_json::from_str(
+            r#"{"method": "GET", "pattern": "/", "usages": [], "mode": "authorize"}"#,
+        )
+        .unwrap();
+        assert_eq!(rule.mode(), Some(Mode::Authorize));
+
+        let rule: MappingRule =
+            serde_json::from_str(r#"{"method": "GET", "pattern": "/", "usages": []}"#).unwrap();
+        assert_eq!(rule.mode(), None);
+
+        for (mode, expected) in &[
+            ("authrep", Mode::AuthRep),
+            ("report", Mode::Report),
+            ("deferred_report", Mode::DeferredReport),
+        ] {
+            let json = format!("{:?}", mode);
+            assert_eq!(
+                serde_json::from_str::<Mode>(json.as_str()).unwrap(),
+                *expected
+            );
+        }
+        assert!(serde_json::from_str::<Mode>(r#""auth_rep""#).is_err());
+    }
 }
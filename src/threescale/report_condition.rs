@@ -0,0 +1,138 @@
+use core::convert::TryFrom;
+
+use proxy_wasm::traits::HttpContext;
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::{process_operations, Operation};
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusError {
+    #[error("invalid status `{0}`, expected a code such as 404 or a class such as 2xx")]
+    Invalid(String),
+}
+
+// Status codes are accepted as numbers or strings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum StatusValue {
+    Code(u32),
+    Str(String),
+}
+
+// An upstream response status code or class of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "StatusValue", into = "String")]
+pub enum Status {
+    Code(u32),
+    // the first digit of the codes in the class
+    Class(u32),
+}
+
+impl TryFrom<StatusValue> for Status {
+    type Error = StatusError;
+
+    fn try_from(value: StatusValue) -> Result<Self, Self::Error> {
+        let value = match value {
+            StatusValue::Code(code) => code.to_string(),
+            StatusValue::Str(s) => s,
+        };
+        let status = value.trim().to_ascii_lowercase();
+
+        let parsed = match status.strip_suffix("xx") {
+            Some(class) if class.len() == 1 => class.parse::<u32>().ok().map(Self::Class),
+            Some(_) => None,
+            None if status.len() == 3 => status.parse::<u32>().ok().map(Self::Code),
+            None => None,
+        };
+
+        match parsed {
+            Some(Self::Class(class)) if (1..=5).contains(&class) => Ok(Self::Class(class)),
+            Some(Self::Code(code)) if (100..600).contains(&code) => Ok(Self::Code(code)),
+            _ => Err(StatusError::Invalid(value)),
+        }
+    }
+}
+
+impl From<Status> for String {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Code(code) => code.to_string(),
+            Status::Class(class) => format!("{}xx", class),
+        }
+    }
+}
+
+impl Status {
+    pub fn matches(&self, code: u32) -> bool {
+        match *self {
+            Self::Code(c) => c == code,
+            Self::Class(class) => code / 100 == class,
+        }
+    }
+}
+
+// A response header whose value has to pass the operations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderCheck {
+    pub name: String,
+    #[serde(default)]
+    pub ops: Vec<Operation>,
+}
+
+// Decides whether to report the usages of a request once upstream responds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportCondition {
+    // any of these statuses, if present
+    #[serde(default)]
+    pub status: Option<Vec<Status>>,
+    // all of these checks
+    #[serde(default)]
+    pub headers: Vec<HeaderCheck>,
+}
+
+impl ReportCondition {
+    pub fn is_satisfied(&self, ctx: &dyn HttpContext, code: Option<u32>) -> bool {
+        if let Some(statuses) = self.status.as_ref() {
+            let status_matched = code.map_or(false, |code| {
+                statuses.iter().any(|status| status.matches(code))
+            });
+            if !status_matched {
+                return false;
+            }
+        }
+
+        self.headers.iter().all(|check| {
+            ctx.get_http_response_header(check.name.as_str())
+                .map_or(false, |value| {
+                    process_operations(ctx, vec![value.into()], check.ops.as_slice()).is_ok()
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_statuses() {
+        let statuses = serde_json::from_str::<Vec<Status>>(r#"["2xx", 404, "503", "3XX"]"#)
+            .expect("failed to parse statuses");
+        assert_eq!(
+            statuses,
+            vec![
+                Status::Class(2),
+                Status::Code(404),
+                Status::Code(503),
+                Status::Class(3)
+            ]
+        );
+        assert!(statuses[0].matches(204));
+        assert!(!statuses[0].matches(500));
+        assert!(statuses[1].matches(404));
+
+        for invalid in &[r#""6xx""#, r#""20x""#, "42", r#""abc""#, "1000"] {
+            assert!(serde_json::from_str::<Status>(invalid).is_err());
+        }
+    }
+}
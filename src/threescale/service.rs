@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
-use super::{Credentials, MappingRule};
+use super::service_matcher::{ServiceMatcher, Specificity};
+use super::{Credentials, CredentialsPolicy, MappingRule, ReportCondition};
+use crate::configuration::ErrorResponses;
+use crate::proxy::request_headers::RequestHeaders;
 use crate::util::glob::GlobPatternSet;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -30,6 +35,79 @@ impl Default for Environment {
     }
 }
 
+// What to do with requests when backend cannot be reached or fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnBackendError {
+    Deny,
+    Allow,
+    // allow applications whose last known authorization succeeded
+    AllowWithCache,
+}
+
+impl Default for OnBackendError {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+// Which calls to backend are made for requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    // authorize and report in a single call
+    #[serde(rename = "authrep")]
+    AuthRep,
+    // authorize without reporting usages
+    Authorize,
+    // report usages without waiting for or enforcing an authorization
+    Report,
+    // authorize, then report along with the upstream response code
+    DeferredReport,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::AuthRep
+    }
+}
+
+// Logs of requests and responses sent along deferred reports.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportLog {
+    #[serde(default = "defaults::max_size")]
+    pub max_size: usize,
+}
+
+mod defaults {
+    pub(super) const fn max_size() -> usize {
+        1024
+    }
+}
+
+impl ReportLog {
+    // Truncates the log to at most max_size bytes on a char boundary.
+    pub fn truncate<'l>(&self, log: &'l str) -> &'l str {
+        if log.len() <= self.max_size {
+            return log;
+        }
+        let mut end = self.max_size;
+        while !log.is_char_boundary(end) {
+            end -= 1;
+        }
+        &log[..end]
+    }
+}
+
+// Data about the authorized application to send upstream in request headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamData {
+    Plan,
+    AppId,
+    UsageReports,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub id: String,
@@ -38,8 +116,23 @@ pub struct Service {
     pub token: Option<String>,
     #[serde(default)]
     pub authorities: GlobPatternSet,
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matcher: Option<ServiceMatcher>,
     pub credentials: Credentials,
     #[serde(default)]
+    pub credentials_policy: CredentialsPolicy,
+    #[serde(default)]
+    pub on_backend_error: OnBackendError,
+    #[serde(default)]
+    pub mode: Mode,
+    pub report_log: Option<ReportLog>,
+    pub report_condition: Option<ReportCondition>,
+    #[serde(default)]
+    pub error_responses: ErrorResponses,
+    // header names mapped to the data they carry
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub upstream_headers: BTreeMap<String, UpstreamData>,
+    #[serde(default)]
     pub mapping_rules: Vec<MappingRule>,
 }
 
@@ -63,11 +156,97 @@ impl Service {
         &self.credentials
     }
 
+    pub fn credentials_policy(&self) -> &CredentialsPolicy {
+        &self.credentials_policy
+    }
+
+    pub fn on_backend_error(&self) -> OnBackendError {
+        self.on_backend_error
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn report_log(&self) -> Option<&ReportLog> {
+        self.report_log.as_ref()
+    }
+
+    pub fn report_condition(&self) -> Option<&ReportCondition> {
+        self.report_condition.as_ref()
+    }
+
+    pub fn error_responses(&self) -> &ErrorResponses {
+        &self.error_responses
+    }
+
+    pub fn upstream_headers(&self) -> &BTreeMap<String, UpstreamData> {
+        &self.upstream_headers
+    }
+
     pub fn mapping_rules(&self) -> &Vec<MappingRule> {
         self.mapping_rules.as_ref()
     }
 
+    // The amount of request body to buffer to look up credentials and compute
+    // usage deltas in it, taking every mapping rule into account since which
+    // ones match is not known until then.
+    pub fn body_limit(&self, rh: &RequestHeaders) -> Option<usize> {
+        let content_length = rh.content_length();
+        self.mapping_rules
+            .iter()
+            .flat_map(|rule| rule.usages())
+            .filter_map(|usage| usage.body_limit(content_length))
+            .chain(self.credentials.body_limit())
+            .max()
+    }
+
     pub fn match_authority(&self, authority: &str) -> bool {
         self.authorities.is_match(authority)
     }
+
+    // Returns how specifically the request matches this service, if it does.
+    pub fn match_request(
+        &self,
+        authority: &str,
+        method: &str,
+        path: &str,
+        rh: &RequestHeaders,
+    ) -> Option<Specificity> {
+        if !self.match_authority(authority) {
+            return None;
+        }
+
+        match self.matcher.as_ref() {
+            Some(matcher) if !matcher.is_match(method, path, rh) => None,
+            Some(matcher) => Some(matcher.specificity()),
+            None => Some(ServiceMatcher::default().specificity()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_deserializes_deferred_report_settings() {
+        let mode: Mode = serde_json::from_str(r#""deferred_report""#).unwrap();
+        assert_eq!(mode, Mode::DeferredReport);
+
+        let log: ReportLog = serde_json::from_str("{}").unwrap();
+        assert_eq!(log.max_size, 1024);
+        let log: ReportLog = serde_json::from_str(r#"{ "max_size": 16 }"#).unwrap();
+        assert_eq!(log.max_size, 16);
+    }
+
+    #[test]
+    fn it_truncates_logs_on_char_boundaries() {
+        let log = ReportLog { max_size: 4 };
+        assert_eq!(log.truncate("GET"), "GET");
+        assert_eq!(log.truncate("GET /"), "GET ");
+        // 'é' takes bytes 3 and 4
+        assert_eq!(log.truncate("abcé"), "abc");
+        assert_eq!(ReportLog { max_size: 0 }.truncate("é"), "");
+    }
 }
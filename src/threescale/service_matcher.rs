@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::request_headers::RequestHeaders;
+use crate::util::glob::GlobPatternSet;
+
+// A header whose value has to match any of the patterns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderMatcher {
+    pub name: String,
+    pub values: GlobPatternSet,
+}
+
+// Further conditions for requests to belong to a service, so that services
+// can share authorities.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceMatcher {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderMatcher>,
+}
+
+// Compared to prefer longer path prefixes, then more header conditions, and
+// then method restrictions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity(usize, usize, bool);
+
+impl ServiceMatcher {
+    pub fn is_match(&self, method: &str, path: &str, rh: &RequestHeaders) -> bool {
+        self.path_prefix
+            .as_deref()
+            .map_or(true, |prefix| path_has_prefix(path, prefix))
+            && (self.methods.is_empty()
+                || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && self.headers.iter().all(|header| {
+                rh.get_all(header.name.to_ascii_lowercase().as_str())
+                    .any(|value| header.values.is_match(value))
+            })
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        Specificity(
+            self.path_prefix.as_ref().map_or(0, |prefix| prefix.len()),
+            self.headers.len(),
+            !self.methods.is_empty(),
+        )
+    }
+}
+
+// Prefixes only match whole path segments, so /v1 does not match /v10.
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_matches_path_prefixes_on_segments() {
+        assert!(path_has_prefix("/v1", "/v1"));
+        assert!(path_has_prefix("/v1/products", "/v1"));
+        assert!(path_has_prefix("/v1/products", "/v1/"));
+        assert!(path_has_prefix("/anything", "/"));
+        assert!(!path_has_prefix("/v10", "/v1"));
+        assert!(!path_has_prefix("/v2/products", "/v1"));
+    }
+
+    #[test]
+    fn it_ranks_specificity() {
+        let matcher = |prefix: Option<&str>, methods: &[&str]| ServiceMatcher {
+            path_prefix: prefix.map(Into::into),
+            methods: methods.iter().map(|&m| m.into()).collect(),
+            headers: vec![],
+        };
+
+        assert!(
+            matcher(Some("/v1/products"), &[]).specificity()
+                > matcher(Some("/v1"), &["GET"]).specificity()
+        );
+        assert!(
+            matcher(Some("/v1"), &["GET"]).specificity() > matcher(Some("/v1"), &[]).specificity()
+        );
+        assert!(matcher(None, &["GET"]).specificity() > ServiceMatcher::default().specificity());
+    }
+}
@@ -1,9 +1,56 @@
+use proxy_wasm::traits::HttpContext;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+use crate::configuration::{process_operations, Operation, Source, DEFAULT_BODY_MAX_SIZE};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub name: String,
-    pub delta: i64,
+    pub delta: Delta,
+}
+
+// Either a fixed amount or one computed for each request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Delta {
+    Static(i64),
+    Computed(Computed),
+}
+
+impl From<i64> for Delta {
+    fn from(delta: i64) -> Self {
+        Self::Static(delta)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Computed {
+    // the first value found by the lookup queries
+    Request(Vec<Source>),
+    // the size of the request body in bytes
+    RequestBodySize,
+    // only known once upstream responds
+    Response(ResponseHeader),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseHeader {
+    pub header: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops: Option<Vec<Operation>>,
+}
+
+impl ResponseHeader {
+    pub fn resolve(&self, ctx: &dyn HttpContext) -> Option<i64> {
+        let value = ctx.get_http_response_header(self.header.as_str())?;
+        let values = match self.ops.as_ref() {
+            Some(ops) => process_operations(ctx, vec![value.into()], ops.as_slice()).ok()?,
+            None => vec![value.into()],
+        };
+
+        values.first().and_then(|value| parse_delta(value))
+    }
 }
 
 impl Usage {
@@ -11,7 +58,94 @@ impl Usage {
         self.name.as_str()
     }
 
-    pub const fn delta(&self) -> i64 {
-        self.delta
+    pub fn delta(&self) -> &Delta {
+        &self.delta
+    }
+
+    // The amount of request body to buffer to compute the delta, if it needs
+    // the body. Bodies sent without a length are buffered to be sized.
+    pub fn body_limit(&self, content_length: Option<u64>) -> Option<usize> {
+        match &self.delta {
+            Delta::Computed(Computed::Request(sources)) => {
+                sources.iter().filter_map(Source::body_limit).max()
+            }
+            Delta::Computed(Computed::RequestBodySize) if content_length.is_none() => {
+                Some(DEFAULT_BODY_MAX_SIZE)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Parses a computed delta, ignoring surrounding whitespace. Negative values
+// are rejected, since they would decrease usages.
+pub fn parse_delta(value: &str) -> Option<i64> {
+    use core::convert::TryFrom;
+
+    value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|delta| i64::try_from(delta).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delta(json: &str) -> Delta {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn it_deserializes_static_and_computed_deltas() {
+        assert!(matches!(delta("3"), Delta::Static(3)));
+        assert!(matches!(
+            delta(r#"{"request": [{"header": {"keys": ["x-tokens"]}}]}"#),
+            Delta::Computed(Computed::Request(sources)) if sources.len() == 1
+        ));
+        assert!(matches!(
+            delta(r#""request_body_size""#),
+            Delta::Computed(Computed::RequestBodySize)
+        ));
+        assert!(matches!(
+            delta(r#"{"response": {"header": "x-tokens-used"}}"#),
+            Delta::Computed(Computed::Response(ResponseHeader { header, ops: None }))
+                if header == "x-tokens-used"
+        ));
+        assert!(serde_json::from_str::<Delta>(r#"{"upstream": {}}"#).is_err());
+    }
+
+    #[test]
+    fn it_buffers_bodies_for_body_deltas() {
+        let usage = |delta: &str| Usage {
+            name: "hits".into(),
+            delta: self::delta(delta),
+        };
+
+        let body = usage(
+            r#"{"request": [{"header": {"keys": ["x-tokens"]}}, {"body": {"keys": ["tokens"], "max_size": 512}}]}"#,
+        );
+        assert_eq!(body.body_limit(None), Some(512));
+        assert_eq!(body.body_limit(Some(12)), Some(512));
+
+        let size = usage(r#""request_body_size""#);
+        assert_eq!(size.body_limit(None), Some(DEFAULT_BODY_MAX_SIZE));
+        assert_eq!(size.body_limit(Some(12)), None);
+
+        assert_eq!(usage("1").body_limit(None), None);
+        assert_eq!(
+            usage(r#"{"request": [{"header": {"keys": ["x-tokens"]}}]}"#).body_limit(None),
+            None
+        );
+    }
+
+    #[test]
+    fn it_parses_non_negative_deltas() {
+        assert_eq!(parse_delta(" 42\n"), Some(42));
+        assert_eq!(parse_delta("0"), Some(0));
+        assert_eq!(parse_delta("-1"), None);
+        assert_eq!(parse_delta("1.5"), None);
+        assert_eq!(parse_delta("18446744073709551615"), None);
     }
 }
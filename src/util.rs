@@ -1,3 +1,5 @@
 pub mod glob;
+pub mod jwt;
+pub mod path_template;
 pub mod rand;
 pub mod serde;
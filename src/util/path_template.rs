@@ -0,0 +1,133 @@
+// Path templates using the `{param}` syntax of 3scale mapping rule patterns.
+//
+// Templates are matched as mapping rule patterns are, so they match path
+// prefixes unless they end with a `$`. Each `{param}` placeholder taking a
+// whole path segment captures the value of that segment.
+//
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use threescalers::http::mapping_rule::{Method, RestRule};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid path template: {0}")]
+    Pattern(#[from] threescalers::Error),
+    #[error("parameter placeholder `{0}` does not take a whole path segment")]
+    Placeholder(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    rule: RestRule,
+    // parameter names along with the index of the segment they take
+    params: Vec<(usize, String)>,
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value.as_str())
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(pt: PathTemplate) -> Self {
+        pt.rule.pattern()
+    }
+}
+
+impl PathTemplate {
+    pub fn new(template: &str) -> Result<Self, Error> {
+        let pattern = template.strip_suffix('$').unwrap_or(template);
+        let params = pattern
+            .split('/')
+            .enumerate()
+            .filter(|(_, segment)| segment.contains('{') || segment.contains('}'))
+            .map(|(idx, segment)| {
+                segment
+                    .strip_prefix('{')
+                    .and_then(|name| name.strip_suffix('}'))
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty() && !name.contains(&['{', '}'][..]))
+                    .map(|name| (idx, name.to_string()))
+                    .ok_or_else(|| Error::Placeholder(segment.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            rule: RestRule::new(Method::from("any"), template)?,
+            params,
+        })
+    }
+
+    // Returns the parameter names along with their (percent-encoded) values.
+    pub fn captures<'s>(&self, path: &'s str) -> Option<Vec<(&str, &'s str)>> {
+        if !self.rule.matches_path_with_qs(path) {
+            return None;
+        }
+
+        let segments = path.split('/').collect::<Vec<_>>();
+        self.params
+            .iter()
+            .map(|(idx, name)| {
+                segments
+                    .get(*idx)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (name.as_str(), *value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_captures_path_template_variables() -> Result<(), Error> {
+        let fixtures = [
+            (
+                "/v1/{user_key}/orders",
+                "/v1/abc/orders",
+                Some(vec![("user_key", "abc")]),
+            ),
+            (
+                "/v1/{user_key}/orders",
+                "/v1/abc/orders/1",
+                Some(vec![("user_key", "abc")]),
+            ),
+            ("/v1/{user_key}/orders$", "/v1/abc/orders/1", None),
+            ("/v1/{user_key}/orders", "/v1//orders", None),
+            ("/v1/{user_key}/orders", "/v2/abc/orders", None),
+            (
+                "/apps/{app_id}/{app_key}",
+                "/apps/id/key/rest",
+                Some(vec![("app_id", "id"), ("app_key", "key")]),
+            ),
+            ("/a.b/{key}", "/axb/k", None),
+        ];
+
+        for (template, path, expected) in fixtures.iter() {
+            let pt = PathTemplate::new(template)?;
+            assert_eq!(&pt.captures(path), expected);
+        }
+
+        assert!(matches!(
+            PathTemplate::new("/v1/{user_key"),
+            Err(Error::Placeholder(segment)) if segment == "{user_key"
+        ));
+        assert!(matches!(
+            PathTemplate::new("/v1/{}/orders"),
+            Err(Error::Placeholder(_))
+        ));
+        assert!(matches!(
+            PathTemplate::new("/v1/{id}.json"),
+            Err(Error::Placeholder(_))
+        ));
+
+        Ok(())
+    }
+}